@@ -0,0 +1,62 @@
+//! Trusted dealer for Handel's threshold mode
+//!
+//! Creates the key shares of a t-of-n threshold BLS key. The share of the validator with ID `i` is
+//! printed as its serialized key pair, which can be passed to `--secret-key`.
+
+#[macro_use]
+extern crate clap;
+extern crate failure;
+extern crate hex;
+extern crate rand;
+
+extern crate beserial;
+extern crate handel;
+
+use clap::{App, Arg};
+use failure::Error;
+
+use beserial::Serialize;
+use handel::ThresholdSetup;
+
+
+fn run() -> Result<(), Error> {
+    let matches = App::new("handel-dealer")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Creates the key shares of a threshold key")
+        .arg(Arg::with_name("threshold")
+            .long("threshold")
+            .short("t")
+            .value_name("NUM")
+            .takes_value(true)
+            .required(true)
+            .help("Number of partial signatures needed for a signature"))
+        .arg(Arg::with_name("shares")
+            .long("shares")
+            .short("n")
+            .value_name("NUM")
+            .takes_value(true)
+            .required(true)
+            .help("Number of key shares, i.e. validators"))
+        .get_matches();
+
+    let threshold: usize = matches.value_of("threshold").unwrap().parse()?;
+    let num_shares: usize = matches.value_of("shares").unwrap().parse()?;
+
+    let setup = ThresholdSetup::deal(threshold, num_shares, &mut rand::thread_rng())?;
+
+    println!("group public key: {}", hex::encode(setup.key.group_public_key.serialize_to_vec()));
+    for (id, share) in setup.shares.iter().enumerate() {
+        println!("share {}: {}", id, hex::encode(share.serialize_to_vec()));
+    }
+
+    Ok(())
+}
+
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
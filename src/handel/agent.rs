@@ -3,69 +3,31 @@ use std::net::SocketAddr;
 use std::io::Error as IoError;
 use std::io::ErrorKind;
 
-use parking_lot::{RwLock, RwLockUpgradableReadGuard, RwLockWriteGuard};
-use futures::{Future, future, Stream, IntoFuture};
+use parking_lot::{Mutex, RwLock};
+use futures::{Future, future, Stream};
 use futures::future::Either;
-use tokio::timer::Interval;
-use futures::sync::mpsc::{SendError, UnboundedSender};
+use std::time::Instant;
+
+use tokio::timer::{Interval, Delay};
+use futures::sync::mpsc::{UnboundedSender, UnboundedReceiver};
 use futures::sync::oneshot::{Sender, channel, Receiver};
 
-use beserial::Serialize;
-use bls::bls12_381::Signature;
+use collections::bitset::BitSet;
 
 use crate::handel::{
-    IdentityRegistry, Message, Config, BinomialPartitioner, Level, MultiSignature, Handler,
-    SignatureStore, ReplaceStore, VerifyResult, LinearTimeout, TimeoutStrategy, DummyVerifier,
-    Verifier, ThreadPoolVerifier
+    IdentityRegistry, Message, Config, Handler, LinearTimeout, TimeoutStrategy, Verifier,
+    BoxVerifier, VerifyFuture, SessionId, Shutdown, ShutdownSignal, AgentEvent, EventBroadcaster,
+    HandelResult, PartialResult, CatchUpRequest, Packet, Protocol, Input, Output, Verification,
+    VerificationId, Broadcaster, FinalSignature, ImprovedSignature, Evidence,
 };
 
 
-#[derive(Clone, Debug)]
-enum Todo {
-    Individual { signature: Signature, level: usize, origin: usize },
-    Multi { signature: MultiSignature, level: usize, votes: usize }
-}
-
-impl Todo {
-    pub fn evaluate(&self, store: &ReplaceStore) -> usize {
-        match self {
-            Todo::Multi { signature, level, votes } => store.evaluate_multisig(signature, *level, *votes),
-            Todo::Individual { signature, level, origin } => store.evaluate_individual(signature, *level, *origin)
-        }
-    }
-
-    pub fn put(self, store: &mut ReplaceStore) {
-        match self {
-            Todo::Individual { signature, level, origin } => {
-                store.put_individual(signature, level, origin)
-            }
-            Todo::Multi { signature, level, votes: _ } => {
-                store.put_multisig(signature, level)
-            }
-        }
-    }
-
-    pub fn level(&self) -> usize {
-        *match self {
-            Todo::Individual { signature: _, level, origin: _ } => level,
-            Todo::Multi { signature: _, level, votes: _ } => level,
-        }
-    }
-}
-
-
-
-pub struct HandelState {
-    pub done: bool,
-    todos: Vec<Todo>,
-    pub store: ReplaceStore,
-}
-
-type HandelResult = Result<MultiSignature, ()>;
+type ProcessFuture = Box<dyn Future<Item=(), Error=()> + Send>;
 
+/// Runs the Handel `Protocol` with tokio timers, a thread pool verifier and a UDP network.
 pub struct HandelAgent {
-    /// State that is modified from multiple threads
-    state: RwLock<HandelState>,
+    /// The protocol state machine
+    protocol: Mutex<Protocol>,
 
     /// Handel configuration
     config: Config,
@@ -73,73 +35,77 @@ pub struct HandelAgent {
     /// All known identities
     identities: Arc<IdentityRegistry>,
 
-    /// Multi-threaded signature verification
-    verifier: DummyVerifier,
-    //verifier: ThreadPoolVerifier,
+    /// Signature verification, the backend is selected by the config
+    verifier: BoxVerifier,
 
     /// Sink to send messages to other peers
-    sink: UnboundedSender<(Message, SocketAddr)>,
+    sink: UnboundedSender<(Packet, SocketAddr)>,
 
     /// Level timeouts
     timeouts: LinearTimeout,
 
-    /// Our individual signature
-    individual: Signature,
-
-    /// Levels
-    levels: Vec<Level>,
-
     /// Channel to pass final signature
     result_sender: RwLock<Option<Sender<HandelResult>>>,
     result_receiver: RwLock<Option<Receiver<HandelResult>>>,
+
+    /// Channel to pass the maximal signature
+    maximal_sender: RwLock<Option<Sender<HandelResult>>>,
+    maximal_receiver: RwLock<Option<Receiver<HandelResult>>>,
+
+    /// Stops timers and the network when the agent terminates
+    shutdown: Shutdown,
+
+    /// Triggered when we reached the threshold, but keep improving the signature
+    improving: Shutdown,
+
+    /// Triggered when we produced the final signature, but linger to help other peers
+    finished: Shutdown,
+
+    /// Subscribers of the agent's events
+    events: EventBroadcaster,
+
+    /// Subscribers of the improving signatures
+    signatures: Broadcaster<ImprovedSignature>,
+
+    /// The best signature so far. It's sent to new subscribers.
+    best_signature: RwLock<Option<ImprovedSignature>>,
+
+    /// When the agent was spawned
+    started: RwLock<Instant>,
 }
 
 
 impl HandelAgent {
-    pub fn new(config: Config, identities: IdentityRegistry, sink: UnboundedSender<(Message, SocketAddr)>) -> HandelAgent {
-        /*info!("New Handel Agent:");
-        info!(" - ID: {}", config.node_identity.id);
-        info!(" - Address: {}", config.node_identity.address);
-        info!(" - Public Key: {}", hex::encode(config.node_identity.public_key.serialize_to_vec()));*/
-
-        /*info!("Identities (n={}):", identities.len());
-        let mut max_id = 0;
-        for identity in identities.all().iter() {
-            let pk_hex = &hex::encode(identity.public_key.serialize_to_vec())[0..8];
-            info!(" {:>5}: address={}, pubkey={}, weight={}", identity.id, identity.address, pk_hex, identity.weight);
-            max_id = max_id.max(identity.id);
-        }*/
-        let max_id = identities.all().iter()
-            .map(|identity| identity.id)
-            .max()
-            .expect("No identities");
-
-        // initialize EVERYTHING!
+    pub fn new(config: Config, identities: IdentityRegistry, sink: UnboundedSender<(Packet, SocketAddr)>) -> HandelAgent {
         let identities = Arc::new(identities);
-        let partitioner = Arc::new(BinomialPartitioner::new(config.node_identity.id, max_id));
-        let levels = Level::create_levels(&config, Arc::clone(&partitioner));
-        let store = ReplaceStore::new(Arc::clone(&partitioner));
-        //let verifier = ThreadPoolVerifier::new(config.threshold, config.message_hash.clone(), Arc::clone(&identities), None);
-        let verifier = DummyVerifier::new(config.threshold, Arc::clone(&identities));
-        let individual = config.individual_signature();
+        let verifier = config.create_verifier(Arc::clone(&identities));
+        Self::with_verifier(config, identities, sink, verifier)
+    }
+
+    pub fn with_verifier(config: Config, identities: Arc<IdentityRegistry>, sink: UnboundedSender<(Packet, SocketAddr)>, verifier: BoxVerifier) -> HandelAgent {
+        let protocol = Protocol::new(config.clone(), Arc::clone(&identities));
         let timeouts = LinearTimeout::new(config.timeout);
         let (result_sender, result_receiver) = channel();
+        let (maximal_sender, maximal_receiver) = channel();
 
         HandelAgent {
-            state: RwLock::new(HandelState {
-                done: false,
-                todos: Vec::new(),
-                store,
-            }),
+            protocol: Mutex::new(protocol),
             config,
             identities,
             verifier,
             sink,
             timeouts,
-            individual,
-            levels,
             result_sender: RwLock::new(Some(result_sender)),
             result_receiver: RwLock::new(Some(result_receiver)),
+            maximal_sender: RwLock::new(Some(maximal_sender)),
+            maximal_receiver: RwLock::new(Some(maximal_receiver)),
+            shutdown: Shutdown::new(),
+            improving: Shutdown::new(),
+            finished: Shutdown::new(),
+            events: EventBroadcaster::new(),
+            signatures: Broadcaster::new(),
+            best_signature: RwLock::new(None),
+            started: RwLock::new(Instant::now()),
         }
     }
 
@@ -147,166 +113,163 @@ impl HandelAgent {
         self.result_receiver.write().take()
     }
 
-    fn send_to(&self, to: Vec<usize>, multisig: MultiSignature, individual: Option<Signature>, level: usize) -> Result<(), SendError<(Message, SocketAddr)>> {
-        let message = Message {
-            origin: self.config.node_identity.id as u16,
-            level: level as u8,
-            multisig,
-            individual,
-        };
+    /// The signature after the agent kept improving it past the threshold. See
+    /// `Config::extra_time`.
+    pub fn maximal_signature(&self) -> Option<Receiver<HandelResult>> {
+        self.maximal_receiver.write().take()
+    }
 
-        //debug!("Sending to {:?}: {:?}", to, message);
+    /// Subscribes to the agent's events. The stream ends when the agent stops.
+    pub fn events(&self) -> UnboundedReceiver<AgentEvent> {
+        self.events.subscribe()
+    }
 
-        for id in to {
-            if id == self.config.node_identity.id {
-                continue;
-            }
-            if let Some(identity) = self.identities.get_by_id(id) {
-                self.sink.unbounded_send((message.clone(), identity.address.clone()))?;
-            }
-            else {
-                error!("Unknown identity: id={}", id);
-            }
+    /// Subscribes to the combined signature of all levels. Every signature is strictly better
+    /// than the previous one, starting with the best one so far. The stream ends when the agent
+    /// stops.
+    pub fn signatures(&self) -> UnboundedReceiver<ImprovedSignature> {
+        // NOTE: Hold the lock, so that we don't miss a signature that is emitted meanwhile
+        let best_signature = self.best_signature.read();
+        match best_signature.as_ref() {
+            Some(best) => self.signatures.subscribe_with(best.clone()),
+            None => self.signatures.subscribe(),
         }
+    }
 
-        Ok(())
+    pub fn is_done(&self) -> bool {
+        self.protocol.lock().is_done()
     }
 
-    fn on_timeout(&self, level: usize) {
-        self.start_level(level);
+    pub fn session(&self) -> SessionId {
+        self.config.session
     }
 
-    fn start_level(&self, level: usize) {
-        debug!("Starting level {}", level);
+    /// Peers that sent too many invalid contributions
+    pub fn blacklist(&self) -> BitSet {
+        self.protocol.lock().blacklist().clone()
+    }
 
-        let level = self.levels.get(level)
-            .unwrap_or_else(|| panic!("Timeout for invalid level {}", level));
+    /// Evidence of all misbehaviour that was detected so far
+    pub fn evidence(&self) -> Vec<Evidence> {
+        self.protocol.lock().evidence().to_vec()
+    }
 
-        level.start();
-        if level.id > 0 {
-            if let Some(best) = self.state.read().store.combined(level.id - 1) {
-                self.send_update(best, level, self.config.peer_count);
-            }
+    /// Stops the agent. This tears down all timers and networks that use the agent's shutdown
+    /// signal.
+    pub fn stop(&self) {
+        let outputs = self.protocol.lock().handle(Input::Stop);
+        for output in outputs {
+            // NOTE: Stopping never requests verifications, so there is nothing to wait for.
+            self.dispatch(output);
         }
+    }
 
+    pub fn is_stopped(&self) -> bool {
+        self.shutdown.is_triggered()
     }
 
-    /// Periodic update:
-    ///  - check if timeout for level is reached. TODO: This is done with `on_timeout`
-    ///  - send a new packet ???
-    fn on_update(&self) {
-        let state = self.state.read();
-
-        // NOTE: Skip level 0
-        for level in self.levels.iter().skip(1) {
-            //debug!("send update for level {}", level.id);
-            // send update
-            if let Some(multisig) = state.store.combined(level.id - 1) {
-                self.send_update(multisig, &level, self.config.update_count);
-            }
-        }
+    /// The best combined signature and the completion of all levels
+    pub fn partial_result(&self) -> PartialResult {
+        self.protocol.lock().partial_result()
     }
 
-    fn check_completed_level(&self, todo: &Todo) {
-        debug!("check_completed_level: {:?}", todo);
+    pub fn shutdown_signal(&self) -> ShutdownSignal {
+        self.shutdown.signal()
+    }
 
-        if let Some(level) = self.levels.get(todo.level()) {
-            let state = self.state.read();
+    /// Passes `input` to the protocol and carries out its outputs. The returned future finishes
+    /// once all requested verifications are done and their results were passed to the protocol.
+    fn handle(this: &Arc<Self>, input: Input) -> ProcessFuture {
+        let outputs = this.protocol.lock().handle(input);
+
+        let verifications = outputs.into_iter()
+            .filter_map(|output| this.dispatch(output))
+            .map(|(id, verification)| {
+                let agent = Arc::clone(this);
+                Box::new(verification.and_then(move |result| {
+                    HandelAgent::handle(&agent, Input::Verified { id, result })
+                })) as ProcessFuture
+            })
+            .collect::<Vec<ProcessFuture>>();
 
-            {
-                let mut level_state = level.state.write();
+        Box::new(future::join_all(verifications).map(|_| ()))
+    }
 
-                if level_state.receive_completed {
-                    debug!("check_completed_level: receive_completed=true");
-                    return
+    /// Carries out an output of the protocol. If it requests a verification, the verification
+    /// future is returned.
+    fn dispatch(&self, output: Output) -> Option<(VerificationId, VerifyFuture)> {
+        match output {
+            Output::Send { to, packet } => {
+                if let Some(identity) = self.identities.get_by_id(to) {
+                    self.sink.unbounded_send((packet, identity.address.clone()))
+                        .unwrap_or_else(|e| error!("Failed to send message to {}", e.into_inner().1));
                 }
-
-                let best = state.store.best(todo.level())
-                    .unwrap_or_else(|| panic!("We should have received the best signature for level {}", todo.level()));
-
-                debug!("check_completed_level: level={}, best.len={}, num_peers={}", level.id, best.len(), level.num_peers());
-                if best.len() == level.num_peers() {
-                    //info!("Level {} complete", todo.level());
-                    level_state.receive_completed = true;
-
-                    if todo.level() + 1 < self.levels.len() {
-                        // activate next level
-                        self.start_level(todo.level() + 1)
-                    }
+                else {
+                    error!("Unknown identity: id={}", to);
                 }
-            }
-
-            for i in todo.level() + 1 .. self.levels.len() {
-                if let Some(multisig) = state.store.combined(i - 1) {
-                    let level = self.levels.get(i)
-                        .unwrap_or_else(|| panic!("No level {}", i));
-                    if level.update_signature_to_send(&multisig) {
-                        self.send_update(multisig, &level, self.config.peer_count);
-                    }
+                None
+            },
+            Output::SendTo { address, packet } => {
+                self.sink.unbounded_send((packet, address))
+                    .unwrap_or_else(|e| error!("Failed to send message to {}", e.into_inner().1));
+                None
+            },
+            Output::Verify { id, verification } => {
+                let verification = match verification {
+                    Verification::Individual { value, signature, signer } => self.verifier.verify_individual(value, signature, signer),
+                    Verification::Multisig { value, signature } => self.verifier.verify_multisig(value, signature, false),
+                };
+                Some((id, verification))
+            },
+            Output::Event(event) => {
+                self.events.emit(event);
+                None
+            },
+            Output::Improved(FinalSignature { value, multisig, weight, .. }) => {
+                let improved = ImprovedSignature {
+                    value,
+                    multisig,
+                    weight,
+                    elapsed: self.started.read().elapsed(),
+                };
+                // NOTE: The protocol lock is released before the outputs are carried out, so the
+                // signatures of concurrent inputs might arrive out of order. Only emit the ones
+                // that are strictly better.
+                let mut best_signature = self.best_signature.write();
+                if best_signature.as_ref().map(|best| best.weight < improved.weight).unwrap_or(true) {
+                    self.signatures.emit(improved.clone());
+                    *best_signature = Some(improved);
                 }
-            }
-        }
-        else {
-            error!("Invalid level: {}", todo.level());
-        }
-    }
-
-    fn check_final_signature(&self, _todo: &Todo) {
-        let last_level = self.levels.last().expect("No levels");
-        let state = self.state.upgradable_read();
-
-        if let Some(combined) = state.store.combined(last_level.id) {
-            if combined.len() > self.config.threshold {
-                debug!("Last level combined: {:#?}", combined);
+                None
+            },
+            Output::Result(result) => {
                 if let Some(sender) = self.result_sender.write().take() {
-                    info!("Last level finished receiving");
-
-                    // set done to true
-                    let mut state = RwLockUpgradableReadGuard::upgrade(state);
-                    state.done = true;
-                    let state = RwLockWriteGuard::downgrade(state);
-
-                    sender.send(Ok(combined))
+                    sender.send(result)
                         .unwrap_or_else(|_| error!("Sending final signature to future failed"));
                 }
-                else {
-                    warn!("Already produced final signature");
+                None
+            },
+            Output::ExtraTime => {
+                self.improving.trigger();
+                None
+            },
+            Output::MaximalResult(result) => {
+                if let Some(sender) = self.maximal_sender.write().take() {
+                    sender.send(result)
+                        .unwrap_or_else(|_| error!("Sending maximal signature to future failed"));
                 }
-            }
-        }
-    }
-
-    fn send_update(&self, multisig: MultiSignature, level: &Level, count: usize) {
-        let peer_ids = level.select_next_peers(count);
-
-        let individual = if level.state.read().receive_completed { None } else { Some(self.individual.clone()) };
-
-        self.send_to(peer_ids, multisig, individual, level.id)
-            .unwrap_or_else(|e| error!("Failed to send message to {}", e.into_inner().1))
-    }
-
-    fn get_best_todo(&self) -> Option<(Todo, usize)> {
-        let state = self.state.upgradable_read();
-
-        let mut best_i = 0;
-        let mut best_score = state.todos.first()?.evaluate(&state.store);
-
-        for (i, todo) in state.todos.iter().enumerate().skip(1) {
-            let score = todo.evaluate(&state.store);
-            if score > best_score {
-                best_i = i;
-                best_score = score;
-            }
-        }
-
-        //debug!("Best score: {}", best_score);
-        if best_score > 0 {
-            let mut state = RwLockUpgradableReadGuard::upgrade(state);
-            let best_todo = state.todos.swap_remove(best_i);
-            Some((best_todo, best_score))
-        }
-        else {
-            None
+                None
+            },
+            Output::Linger => {
+                self.finished.trigger();
+                None
+            },
+            Output::Stop => {
+                self.shutdown.trigger();
+                self.events.close();
+                self.signatures.close();
+                None
+            },
         }
     }
 }
@@ -315,180 +278,284 @@ impl HandelAgent {
 pub type AgentFuture = Box<dyn Future<Item=(), Error=()> + Send>;
 
 pub trait AgentProcessor {
-    fn spawn(&self) -> AgentFuture;
+    /// Returns the future that runs the agent and a handle to stop it. The future finishes
+    /// once the agent stopped.
+    fn spawn(&self) -> (AgentFuture, AgentHandle);
 }
 
 impl AgentProcessor for Arc<HandelAgent> {
-    fn spawn(&self) -> AgentFuture {
+    fn spawn(&self) -> (AgentFuture, AgentHandle) {
         let agent = Arc::clone(self);
+        let handle = AgentHandle::new(Arc::clone(self));
+
+        let fut = Box::new(future::lazy(move || {
+            let shutdown = agent.shutdown.signal();
 
-        Box::new(future::lazy(move || {
-            // thread that handles level timeouts
+            // future that handles level timeouts
             let timeouts = {
-                let timeouts = agent.timeouts.timeouts(agent.levels.len());
+                let timeouts = agent.timeouts.timeouts(agent.protocol.lock().num_levels());
                 let agent = Arc::clone(&agent);
-                tokio::spawn(timeouts.for_each(move |level| {
+                shutdown.guard(timeouts.for_each(move |level| {
                     //debug!("Timeout for level {}", level);
-                    agent.on_timeout(level);
-                    future::ok(())
+                    HandelAgent::handle(&agent, Input::Timeout { level })
                 }))
             };
 
-            // thread that periodically updates levels
+            // future that periodically updates levels
             let updates = {
                 let updates = Interval::new_interval(agent.config.update_period);
                 let agent = Arc::clone(&agent);
-                tokio::spawn(updates
+                shutdown.guard(updates
                     .map_err(|e| {
                         error!("Interval error: {}", e);
                     })
                     .for_each(move |_instant| {
                         //debug!("Periodic update: {:?}", t);
-                        agent.on_update();
-                        future::ok::<(), ()>(())
+                        HandelAgent::handle(&agent, Input::Update)
                     })
                 )
             };
 
-            // future that will put our own individual signature into store and notify the agent
-            let init = {
+            // future that stops the agent when the deadline is reached
+            let deadline = if let Some(deadline) = agent.config.deadline {
                 let agent = Arc::clone(&agent);
-                future::lazy(move || {
-                    let mut state = agent.state.write();
+                Either::A(shutdown.guard(Delay::new(Instant::now() + deadline)
+                    .map_err(|e| {
+                        error!("Deadline timer error: {}", e);
+                    })
+                    .and_then(move |_| HandelAgent::handle(&agent, Input::Deadline))
+                ))
+            }
+            else {
+                Either::B(future::ok::<(), ()>(()))
+            };
 
-                    // put own individual signature into store
-                    //state.store.put_individual(agent.individual.clone(), 0, agent.config.node_identity.id);
-                    //state.store.put_multisig(MultiSignature::from_individual(&agent.individual, agent.config.node_identity.id), 0);
-                    let todo = Todo::Individual { signature: agent.individual.clone(), level: 0, origin: agent.config.node_identity.id };
-                    todo.clone().put(&mut state.store);
+            // future that finalizes the signature when the extra time is over
+            let extra_time = if let Some(extra_time) = agent.config.extra_time {
+                let agent = Arc::clone(&agent);
+                Either::A(shutdown.guard(agent.improving.signal()
+                    .and_then(move |_| {
+                        Delay::new(Instant::now() + extra_time)
+                            .map_err(|e| {
+                                error!("Extra time timer error: {}", e);
+                            })
+                    })
+                    .and_then(move |_| HandelAgent::handle(&agent, Input::ExtraTimeOver))
+                ))
+            }
+            else {
+                Either::B(future::ok::<(), ()>(()))
+            };
 
-                    drop(state);
+            // future that stops the agent when the linger period is over
+            let linger = if let Some(linger) = agent.config.linger {
+                let agent = Arc::clone(&agent);
+                Either::A(shutdown.guard(agent.finished.signal()
+                    .and_then(move |_| {
+                        Delay::new(Instant::now() + linger)
+                            .map_err(|e| {
+                                error!("Linger timer error: {}", e);
+                            })
+                    })
+                    .and_then(move |_| HandelAgent::handle(&agent, Input::LingerOver))
+                ))
+            }
+            else {
+                Either::B(future::ok::<(), ()>(()))
+            };
 
-                    // notify
-                    agent.check_completed_level(&todo);
-                    agent.check_final_signature(&todo);
+            *agent.started.write() = Instant::now();
 
-                    // send level 0
-                    let level = agent.levels.get(0)
-                        .expect("Level 0 missing");
-                    agent.send_update(MultiSignature::from_individual(&agent.individual, agent.config.node_identity.id), level, agent.config.peer_count);
+            // future that starts the protocol, i.e. signs and sends to level 0, unless we observe or abstain
+            let init = HandelAgent::handle(&agent, Input::Start);
 
-                    future::ok::<(), ()>(())
-                })
-            };
+            init.and_then(move |_| {
+                timeouts
+                    .join5(updates, deadline, extra_time, linger)
+                    .map(move |_| {
+                        agent.stop();
+                        debug!("Agent terminated");
+                    })
+            })
+        }));
+
+        (fut, handle)
+    }
+}
 
 
-            init.and_then(|_| {
-                timeouts.into_future()
-                    .join(updates.into_future())
-                    .map(|_| ())
-            })
-        }))
+/// Handle to a spawned agent
+#[derive(Clone)]
+pub struct AgentHandle {
+    agent: Arc<HandelAgent>,
+}
+
+impl AgentHandle {
+    fn new(agent: Arc<HandelAgent>) -> Self {
+        Self {
+            agent,
+        }
+    }
+
+    /// Stops the agent and all its timers. Networks that were connected with the agent's
+    /// shutdown signal are closed as well.
+    pub fn stop(&self) {
+        self.agent.stop()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.agent.is_stopped()
+    }
+
+    pub fn shutdown_signal(&self) -> ShutdownSignal {
+        self.agent.shutdown.signal()
+    }
+
+    pub fn agent(&self) -> &Arc<HandelAgent> {
+        &self.agent
     }
 }
 
 
 
 impl Handler for Arc<HandelAgent> {
-    fn on_message(&self, message: Message, _sender_address: SocketAddr) -> Box<dyn Future<Item=(), Error=IoError> + Send> {
-        // we create a future that handles the message
-        let handle_fut = if !self.state.read().done {
-            // deconstruct message
-            let Message {
-                origin,
-                level,
-                multisig,
-                individual,
-            } = message;
-            let origin = origin as usize;
-            let level = level as usize;
-
-            if let Some(level) = self.levels.get(level) {
-                if level.state.read().receive_completed {
-                    return Box::new(future::ok::<(), IoError>(()));
-                }
-            }
-            else {
-                error!("Invalid level in message: {}", level);
-            }
+    fn on_message(&self, message: Message, sender_address: SocketAddr) -> Box<dyn Future<Item=(), Error=IoError> + Send> {
+        // we create a future that handles the message and verifies the contributions that the
+        // verification windows admit
+        Box::new(HandelAgent::handle(self, Input::Received { message, from: sender_address })
+            .map_err(|e| {
+                // Technically nothing here can fail, but we need to handle that case anyway
+                warn!("The signature processing future somehow failed: {:?}", e);
+                IoError::from(ErrorKind::ConnectionReset)
+            }))
+    }
 
-            //info!("Received message from address={} id={} for level={}", sender_address, origin, level);
-
-            // XXX The following code should all be a future. The part that takes ultimately the
-            //     longest will be the signature checking, so we could distribute that over a
-            //     CPU pool.
-
-            // Creates a future that will verify the multisig on a CpuPool and then push it into
-            // the TODOs
-            let this = Arc::clone(&self);
-            let multisig_fut = self.verifier.verify_multisig(multisig.clone(), false)
-                .and_then(move|result| {
-                    match result {
-                        VerifyResult::Ok { votes } => {
-                            this.state.write().todos.push(Todo::Multi { signature: multisig, level, votes });
-                        },
-                        _ => {
-                            warn!("Rejected signature: {:?}", result);
-                            warn!("{:#?}", multisig);
-                        }
-                    }
-                    Ok(())
-                });
-
-            // Creates a future that will verify the individual signature on a CpuPool and then
-            // push it into the TODOs
-            let this = Arc::clone(&self);
-            let individual_fut = if let Some(sig) = individual {
-                Either::A(self.verifier.verify_individual(sig.clone(), origin)
-                    .and_then(move |result| {
-                        match result {
-                            VerifyResult::Ok { votes } => {
-                                assert_eq!(votes, 1);
-                                this.state.write().todos.push(Todo::Individual{ signature: sig, level, origin });
-                            },
-                            _ => {
-                                warn!("Rejected signature: {:?}", result);
-                                warn!("{:#?}", sig);
-                            }
-                        }
-                        Ok(())
-                    }))
-            } else {
-                Either::B(future::ok::<(), ()>(()))
-            };
+    fn on_request(&self, request: CatchUpRequest, sender_address: SocketAddr) -> Box<dyn Future<Item=(), Error=IoError> + Send> {
+        Box::new(HandelAgent::handle(self, Input::CatchUpRequested { request, from: sender_address })
+            .map_err(|e| {
+                warn!("The catch-up request handling future somehow failed: {:?}", e);
+                IoError::from(ErrorKind::ConnectionReset)
+            }))
+    }
+}
 
-            // Creates a future that will first verify the signatures and then gets all good TODOs
-            // and applys them
-            let this = Arc::clone(&self);
-            let process_fut = multisig_fut
-                .join(individual_fut)
-                .and_then(move |_| {
-                    // continuously put best todo into store, until there is no good one anymore
-                    while let Some((todo, score)) = this.get_best_todo() {
-                        //info!("Processing: score={}: {:?}", score, todo);
-                        // TODO: put signature from todo into store - is this correct?
-                        todo.clone().put(&mut this.state.write().store);
-                        this.check_completed_level(&todo);
-                        this.check_final_signature(&todo);
-                    }
-                    Ok(())
-                })
-                .map_err(|e| {
-                    // Technically nothing here can fail, but we need to handle that case anyway
-                    warn!("The signature processing future somehow failed: {:?}", e);
-                    IoError::from(ErrorKind::ConnectionReset)
-                });
-
-            Either::A(process_fut)
-        }
-        else {
-            // we're done, so we don't care
-            //Either::B(future::failed(IoError::from(ErrorKind::ConnectionReset)))
-            Either::B(future::ok::<(), IoError>(()))
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::net::SocketAddr;
+
+    use futures::{Future, Stream};
+    use futures::sync::mpsc::{unbounded, UnboundedReceiver};
+
+    use hash::{Hash, Blake2bHash};
+    use bls::bls12_381::KeyPair;
+
+    use crate::handel::{Config, Message, MultiSignature, Packet, Input, Output, FinalSignature};
+    use crate::handel::testing::{address, create_key_pairs, create_identities};
+    use super::HandelAgent;
+
+    /// Creates the agent of node 0 in a committee of 4 nodes. Returns it with the key pairs of all
+    /// nodes and the receiver of the packets that the agent sends.
+    fn create_agent(threshold: usize, extra_time: Option<Duration>) -> (Arc<HandelAgent>, Vec<KeyPair>, UnboundedReceiver<(Packet, SocketAddr)>) {
+        let key_pairs = create_key_pairs(4);
+        let registry = create_identities(&key_pairs);
+
+        let mut config = Config::for_test(&registry, 0, key_pairs[0].clone(), threshold);
+        config.extra_time = extra_time;
+
+        let (sink, packets) = unbounded();
+        let agent = Arc::new(HandelAgent::new(config, registry, sink));
+
+        (agent, key_pairs, packets)
+    }
+
+    /// The contribution of node `id`, that it sends at `level` from its own address
+    fn contribution(key_pairs: &[KeyPair], id: usize, level: usize) -> Input {
+        let value = "foobar".hash::<Blake2bHash>();
+        let individual = key_pairs[id].sign_hash(value.clone());
+        let message = Message {
+            session: 0,
+            value,
+            origin: id as u16,
+            level: level as u8,
+            multisig: MultiSignature::from_individual(&individual, id),
+            individual: Some(individual),
+            partials: Vec::new(),
+            done: false,
+            origin_signature: None,
         };
+        Input::Received { message, from: address(id) }
+    }
+
+    /// Passes `input` to the agent and waits until all verifications are done
+    fn handle(agent: &Arc<HandelAgent>, input: Input) {
+        HandelAgent::handle(agent, input).wait().unwrap();
+    }
+
+    #[test]
+    fn test_extra_time() {
+        let (agent, key_pairs, _packets) = create_agent(3, Some(Duration::from_secs(1)));
+        let final_signature = agent.final_signature().unwrap();
+        let maximal_signature = agent.maximal_signature().unwrap();
+
+        handle(&agent, Input::Start);
+        handle(&agent, contribution(&key_pairs, 1, 1));
+        handle(&agent, contribution(&key_pairs, 2, 2));
 
-        // box it, so we don't have to bother about the return type
-        Box::new(handle_fut)
+        // the threshold is reached, but we keep improving the signature
+        assert_eq!(final_signature.wait().unwrap().unwrap().weight, 3);
+        assert!(!agent.is_done(), "Agent stopped improving at the threshold");
+
+        // all identities signed, so the maximal signature is done before the extra time is over
+        handle(&agent, contribution(&key_pairs, 3, 2));
+        assert!(agent.is_done());
+        assert_eq!(maximal_signature.wait().unwrap().unwrap().weight, 4);
     }
 
+    #[test]
+    fn test_extra_time_over() {
+        let (agent, key_pairs, _packets) = create_agent(3, Some(Duration::from_secs(1)));
+        let maximal_signature = agent.maximal_signature().unwrap();
+
+        handle(&agent, Input::Start);
+        handle(&agent, contribution(&key_pairs, 1, 1));
+        handle(&agent, contribution(&key_pairs, 2, 2));
+        assert!(!agent.is_done());
+
+        // the maximal signature is the best one when the extra time is over
+        handle(&agent, Input::ExtraTimeOver);
+        assert!(agent.is_done());
+        assert_eq!(maximal_signature.wait().unwrap().unwrap().weight, 3);
+    }
+
+    #[test]
+    fn test_improved_signatures() {
+        let (agent, key_pairs, _packets) = create_agent(4, None);
+        let signatures = agent.signatures();
+
+        handle(&agent, Input::Start);
+        handle(&agent, contribution(&key_pairs, 1, 1));
+        // a duplicate doesn't improve the signature
+        handle(&agent, contribution(&key_pairs, 1, 1));
+        handle(&agent, contribution(&key_pairs, 2, 2));
+        handle(&agent, contribution(&key_pairs, 3, 2));
+
+        // the stream ends when the agent stops
+        assert!(agent.is_stopped());
+        let weights = signatures.collect().wait().unwrap().into_iter()
+            .map(|signature| signature.weight)
+            .collect::<Vec<usize>>();
+        assert_eq!(weights, vec![1, 2, 3, 4]);
+
+        // a signature that arrives late doesn't replace a better one
+        let value = "foobar".hash::<Blake2bHash>();
+        let multisig = MultiSignature::from_individual(&key_pairs[1].sign_hash(value.clone()), 1);
+        agent.dispatch(Output::Improved(FinalSignature { value, multisig, weight: 1, group_signature: None }));
+
+        // new subscribers start with the best signature
+        let (best, _) = agent.signatures().into_future().wait().ok().unwrap();
+        assert_eq!(best.unwrap().weight, 4);
+    }
 }
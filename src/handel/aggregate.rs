@@ -0,0 +1,234 @@
+use std::io::Error as IoError;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use failure::Fail;
+use futures::{Future, future};
+use futures::sync::oneshot::Receiver;
+use rand::random;
+
+use hash::Blake2bHash;
+use bls::bls12_381::KeyPair;
+
+use crate::handel::{
+    Config, Identity, IdentityRegistry, HandelAgent, AgentProcessor, UdpNetwork, VerifierBackend,
+    FinalSignature, SessionId, AggregationError, HandelResult, ThresholdKey, ThresholdError,
+};
+
+
+#[derive(Debug, Fail)]
+pub enum AggregateError {
+    #[fail(display = "Our public key is not in the committee")]
+    NotInCommittee,
+    #[fail(display = "The committee is empty")]
+    EmptyCommittee,
+    #[fail(display = "Failed to initialize network: {}", _0)]
+    Network(#[cause] IoError),
+    #[fail(display = "Aggregation failed: {}", _0)]
+    Aggregation(#[cause] AggregationError),
+    #[fail(display = "Agent was dropped")]
+    Canceled,
+    #[fail(display = "Invalid threshold key: {}", _0)]
+    ThresholdKey(#[cause] ThresholdError),
+}
+
+
+/// Parameters of an aggregation with `aggregate`
+#[derive(Clone, Debug)]
+pub struct AggregateParams {
+    /// Minimum total weight of the signers needed. A signature with exactly this weight is valid.
+    /// With `None` more than two thirds of the committee's weight are needed.
+    pub threshold: Option<usize>,
+
+    /// The session of the aggregation. All members of the committee must use the same session.
+    pub session: SessionId,
+
+    /// Address the network is bound to. With `None` we listen on all interfaces on the port of
+    /// our identity.
+    pub bind_to: Option<SocketAddr>,
+
+    /// Frequency at which updates are sent to peers
+    pub update_period: Duration,
+
+    /// Timeout for levels
+    pub timeout: Duration,
+
+    /// How many peers are contacted at each level
+    pub peer_count: usize,
+
+    /// Whether to immediately send complete aggregates to new peers
+    pub fast_path: bool,
+
+    /// Whether to start all levels immediately and ask peers for their aggregates, e.g. after a
+    /// restart
+    pub catch_up: bool,
+
+    /// The aggregation fails, if it didn't reach the threshold after this time
+    pub deadline: Duration,
+
+    /// Keep improving the signature for this long, after the threshold was reached
+    pub extra_time: Option<Duration>,
+
+    /// Keep helping peers for this long, after we're done
+    pub linger: Option<Duration>,
+
+    /// Backend used to verify incoming signatures. The `Dummy` backend is not allowed.
+    pub verifier: VerifierBackend,
+
+    /// Don't sign the message, but still aggregate and relay the signatures of our peers
+    pub abstain: bool,
+
+    /// Whether we relay our aggregates, when we observe the aggregation with `observe`
+    pub relay: bool,
+
+    /// The threshold key, if our key pair is a share of it
+    pub threshold_key: Option<ThresholdKey>,
+}
+
+impl Default for AggregateParams {
+    fn default() -> Self {
+        Self {
+            threshold: None,
+            session: 0,
+            bind_to: None,
+            update_period: Duration::from_millis(100),
+            timeout: Duration::from_millis(500),
+            peer_count: 10,
+            fast_path: true,
+            catch_up: false,
+            deadline: Duration::from_secs(60),
+            extra_time: None,
+            linger: Some(Duration::from_secs(5)),
+            verifier: VerifierBackend::default(),
+            abstain: false,
+            relay: false,
+            threshold_key: None,
+        }
+    }
+}
+
+impl AggregateParams {
+    /// The configuration of our agent. Without a key pair, or if we abstain, we don't sign.
+    pub fn config(&self, committee: &IdentityRegistry, node_identity: Arc<Identity>, key_pair: Option<KeyPair>, message_hash: Blake2bHash) -> Config {
+        let threshold = self.threshold.unwrap_or_else(|| {
+            let total_weight: usize = committee.all().iter()
+                .map(|identity| identity.weight)
+                .sum();
+            2 * total_weight / 3 + 1
+        });
+
+        Config {
+            threshold,
+            message_hash,
+            multi_value: false,
+            session: self.session,
+            node_identity,
+            observer: false,
+            relay: self.relay,
+            threshold_key: self.threshold_key.clone(),
+            peers: None,
+            contribution: None,
+            disable_shuffling: false,
+            seed: random(),
+            update_count: 1,
+            update_period: self.update_period,
+            timeout: self.timeout,
+            peer_count: self.peer_count,
+            fast_path: self.fast_path,
+            fast_path_count: self.peer_count,
+            catch_up: self.catch_up,
+            blacklist_threshold: 1,
+            window_initial: 16,
+            window_min: 1,
+            window_max: 128,
+            max_todos: 64,
+            deadline: Some(self.deadline),
+            extra_time: self.extra_time,
+            linger: self.linger,
+            key_pair: if self.abstain { None } else { key_pair },
+            verifier: self.verifier.clone(),
+            allow_dummy_verifier: false,
+        }
+    }
+
+    /// The address that the network is bound to
+    pub fn bind_address(&self, node_identity: &Identity) -> SocketAddr {
+        self.bind_to.unwrap_or_else(|| {
+            SocketAddr::new("0.0.0.0".parse().expect("Invalid IP address"), node_identity.address.port())
+        })
+    }
+}
+
+
+/// Aggregates the signatures of `committee` on `message_hash`
+///
+/// Our identity is the member of the committee with the public key of `key_pair`. This sets up the
+/// network, runs an agent and tears both down once the agent stopped. The future resolves with the
+/// maximal signature, i.e. the signature after the extra time, if any. With a threshold key it
+/// contains the signature of the group. It must be polled from within a tokio runtime.
+pub fn aggregate(committee: IdentityRegistry, key_pair: KeyPair, message_hash: Blake2bHash, params: AggregateParams) -> Box<dyn Future<Item=FinalSignature, Error=AggregateError> + Send> {
+    let start = future::lazy(move || -> Result<Receiver<HandelResult>, AggregateError> {
+        let node_identity = committee.get_by_public_key(&key_pair.public)
+            .ok_or(AggregateError::NotInCommittee)?;
+        let bind_to = params.bind_address(&node_identity);
+        let config = params.config(&committee, node_identity, Some(key_pair), message_hash);
+
+        run(config, committee, bind_to)
+    });
+
+    Box::new(start.and_then(resolve))
+}
+
+/// Observes the aggregation of the signatures of `committee` on `message_hash`
+///
+/// We're not part of the committee and don't sign. Instead we ask the committee for its aggregates,
+/// verify and combine them, until we have the final signature. We take the position of the first
+/// member of the committee in the partitioning. With `AggregateParams::bind_to` unset, we listen
+/// on a random port. It must be polled from within a tokio runtime.
+pub fn observe(committee: IdentityRegistry, message_hash: Blake2bHash, params: AggregateParams) -> Box<dyn Future<Item=FinalSignature, Error=AggregateError> + Send> {
+    let start = future::lazy(move || -> Result<Receiver<HandelResult>, AggregateError> {
+        let position = committee.all().into_iter()
+            .min_by_key(|identity| identity.id)
+            .ok_or(AggregateError::EmptyCommittee)?;
+        let bind_to = params.bind_to.unwrap_or_else(|| {
+            SocketAddr::new("0.0.0.0".parse().expect("Invalid IP address"), 0)
+        });
+
+        let mut config = params.config(&committee, position, None, message_hash);
+        config.observer = true;
+
+        run(config, committee, bind_to)
+    });
+
+    Box::new(start.and_then(resolve))
+}
+
+/// Sets up the network and the agent and spawns them. Returns the receiver of the maximal
+/// signature.
+fn run(config: Config, committee: IdentityRegistry, bind_to: SocketAddr) -> Result<Receiver<HandelResult>, AggregateError> {
+    if let Some(threshold_key) = &config.threshold_key {
+        threshold_key.check(&committee)
+            .map_err(AggregateError::ThresholdKey)?;
+    }
+
+    let mut network = UdpNetwork::new();
+    let agent = Arc::new(HandelAgent::new(config, committee, network.sink()));
+    let (agent_fut, handle) = agent.spawn();
+    let maximal = agent.maximal_signature()
+        .expect("Maximal signature of new agent was taken already");
+
+    let network_fut = network.connect(&bind_to, Arc::clone(&agent), handle.shutdown_signal())
+        .map_err(AggregateError::Network)?;
+
+    // the network and the agent run until the agent stopped
+    tokio::spawn(network_fut.join(agent_fut).map(|_| ()));
+
+    Ok(maximal)
+}
+
+fn resolve(maximal: Receiver<HandelResult>) -> impl Future<Item=FinalSignature, Error=AggregateError> {
+    maximal
+        .map_err(|_| AggregateError::Canceled)
+        .and_then(|result| result.map_err(AggregateError::Aggregation))
+}
@@ -0,0 +1,269 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use futures::sync::oneshot::{channel, Sender};
+use futures_cpupool::CpuPool;
+use parking_lot::Mutex;
+use pairing::CurveProjective;
+use pairing::bls12_381::{G1, G2};
+use rand::{thread_rng, Rng};
+use stopwatch::Stopwatch;
+use tokio::timer::Delay;
+
+use hash::Blake2bHash;
+use bls::bls12_381::{Signature, PublicKey};
+
+use crate::handel::{IdentityRegistry, MultiSignature, Verifier, VerifyResult, VerifyFuture};
+
+
+/// A signature that waits to be verified in a batch
+struct BatchItem {
+    /// The (aggregate) signature
+    signature: G1,
+
+    /// The (aggregate) public key of the signers
+    public_key: G2,
+
+    /// Total weight of the signers
+    votes: usize,
+
+    /// Notifies the future that was returned by the verifier
+    sender: Sender<VerifyResult>,
+}
+
+
+/// Verifies signatures in batches
+///
+/// Signatures are batched per message. All signatures of a batch sign the same message, so it can
+/// be checked with only two pairings by verifying a random linear combination of the signatures
+/// against the same combination of the public keys. If a batch is invalid, it's bisected to find
+/// the invalid signatures.
+///
+/// A batch is verified once it reached `batch_size` signatures, or `batch_delay` after its first
+/// signature was queued. This must be used from within a tokio runtime.
+pub struct BatchVerifier {
+    threshold: usize,
+    identities: Arc<IdentityRegistry>,
+    workers: CpuPool,
+    batch_size: usize,
+    batch_delay: Duration,
+
+    /// message hash -> batch
+    pending: Arc<Mutex<BTreeMap<Blake2bHash, Vec<BatchItem>>>>,
+}
+
+impl BatchVerifier {
+    pub fn new(threshold: usize, identities: Arc<IdentityRegistry>, num_workers: Option<usize>, batch_size: usize, batch_delay: Duration) -> Self {
+        let workers = if let Some(n) = num_workers {
+            CpuPool::new(n)
+        } else {
+            CpuPool::new_num_cpus()
+        };
+
+        Self::with_pool(threshold, identities, workers, batch_size, batch_delay)
+    }
+
+    pub fn with_pool(threshold: usize, identities: Arc<IdentityRegistry>, workers: CpuPool, batch_size: usize, batch_delay: Duration) -> Self {
+        Self {
+            threshold,
+            identities,
+            workers,
+            batch_size: batch_size.max(1),
+            batch_delay,
+            pending: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Queues a signature and returns a future for its result
+    fn enqueue(&self, message_hash: Blake2bHash, signature: G1, public_key: G2, votes: usize) -> VerifyFuture {
+        let (sender, receiver) = channel();
+
+        let num_pending = {
+            let mut pending = self.pending.lock();
+            let batch = pending.entry(message_hash.clone()).or_insert_with(Vec::new);
+            batch.push(BatchItem { signature, public_key, votes, sender });
+            batch.len()
+        };
+
+        if num_pending >= self.batch_size {
+            Self::flush_pending(&self.pending, &self.workers, message_hash);
+        }
+        else if num_pending == 1 {
+            // first signature of a new batch: make sure it gets verified even if the batch
+            // doesn't fill up
+            let pending = Arc::clone(&self.pending);
+            let workers = self.workers.clone();
+            tokio::spawn(Delay::new(Instant::now() + self.batch_delay)
+                .map_err(|e| error!("Batch timer error: {}", e))
+                .map(move |_| Self::flush_pending(&pending, &workers, message_hash)));
+        }
+
+        Box::new(receiver.map_err(|_| error!("Batch verification was canceled")))
+    }
+
+    /// Takes all pending signatures for `message_hash` and verifies them on the thread pool
+    fn flush_pending(pending: &Mutex<BTreeMap<Blake2bHash, Vec<BatchItem>>>, workers: &CpuPool, message_hash: Blake2bHash) {
+        let items = match pending.lock().remove(&message_hash) {
+            Some(items) => items,
+            None => return,
+        };
+
+        workers.spawn_fn(move || {
+            let mut stopwatch = Stopwatch::start_new();
+
+            let mut valid = vec![true; items.len()];
+            find_invalid(&message_hash, &items, 0, &mut valid);
+
+            stopwatch.stop();
+            debug!("Took {} ms to verify batch of {} signatures", stopwatch.elapsed_ms(), items.len());
+
+            for (item, valid) in items.into_iter().zip(valid) {
+                let result = if valid {
+                    VerifyResult::Ok { votes: item.votes }
+                }
+                else {
+                    VerifyResult::InvalidSignature
+                };
+                // the receiver might be gone already, so we ignore the error
+                item.sender.send(result).unwrap_or(());
+            }
+
+            future::ok::<(), ()>(())
+        }).forget();
+    }
+}
+
+impl Verifier for BatchVerifier {
+    type Output = VerifyFuture;
+
+    fn verify_individual(&self, message_hash: Blake2bHash, signature: Signature, signer: usize) -> Self::Output {
+        if let Some(identity) = self.identities.get_by_id(signer) {
+            self.enqueue(message_hash, signature.s, identity.public_key.p_pub, identity.weight)
+        }
+        else {
+            Box::new(future::ok(VerifyResult::UnknownSigner { signer }))
+        }
+    }
+
+    fn verify_multisig(&self, message_hash: Blake2bHash, signature: MultiSignature, check_threshold: bool) -> Self::Output {
+        let mut public_key = G2::zero();
+        let mut votes = 0;
+
+        // every validator signed once for all its slots
+        match self.identities.signers(&signature.signers) {
+            Ok(signers) => {
+                for identity in signers {
+                    public_key.add_assign(&identity.public_key.p_pub);
+                    votes += identity.weight;
+                }
+            },
+            Err(signer) => return Box::new(future::ok(VerifyResult::UnknownSigner { signer })),
+        }
+
+        if check_threshold && votes < self.threshold {
+            return Box::new(future::ok(VerifyResult::ThresholdNotReached { votes, threshold: self.threshold }));
+        }
+
+        self.enqueue(message_hash, (signature.signature.0).s, public_key, votes)
+    }
+}
+
+
+/// Verifies a random linear combination of all signatures in `items` against the same
+/// combination of their public keys.
+fn verify_batch(message_hash: &Blake2bHash, items: &[BatchItem]) -> bool {
+    let mut rng = thread_rng();
+    let mut signature = G1::zero();
+    let mut public_key = G2::zero();
+
+    for item in items {
+        // NOTE: The coefficients must not be zero, otherwise an invalid signature could be hidden
+        let r = rng.gen::<u64>() | 1;
+
+        let mut s = item.signature;
+        s.mul_assign(r);
+        signature.add_assign(&s);
+
+        let mut p = item.public_key;
+        p.mul_assign(r);
+        public_key.add_assign(&p);
+    }
+
+    PublicKey { p_pub: public_key }.verify_hash(message_hash.clone(), &Signature { s: signature })
+}
+
+/// Bisects `items` until all invalid signatures are found and marks them in `valid`. `offset`
+/// is the index of the first item of `items` in `valid`.
+fn find_invalid(message_hash: &Blake2bHash, items: &[BatchItem], offset: usize, valid: &mut [bool]) {
+    if items.is_empty() || verify_batch(message_hash, items) {
+        return;
+    }
+
+    if items.len() == 1 {
+        valid[offset] = false;
+    }
+    else {
+        let mid = items.len() / 2;
+        find_invalid(message_hash, &items[.. mid], offset, valid);
+        find_invalid(message_hash, &items[mid ..], offset + mid, valid);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::sync::oneshot::channel;
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
+
+    use hash::{Hash, Blake2bHash};
+    use bls::bls12_381::KeyPair;
+
+    use super::{BatchItem, find_invalid};
+
+    /// Creates `num_items` items that sign "foobar", except for the items at `invalid`, which
+    /// sign "barfoo".
+    fn create_items(num_items: usize, invalid: &[usize]) -> Vec<BatchItem> {
+        let mut csprng = ChaChaRng::from_seed([42; 32]);
+        (0 .. num_items)
+            .map(|i| {
+                let key_pair = KeyPair::generate(&mut csprng);
+                let signed = if invalid.contains(&i) { "barfoo" } else { "foobar" };
+                let (sender, _) = channel();
+                BatchItem {
+                    signature: key_pair.sign_hash(signed.hash::<Blake2bHash>()).s,
+                    public_key: key_pair.public.p_pub,
+                    votes: 1,
+                    sender,
+                }
+            })
+            .collect()
+    }
+
+    fn invalid_indices(num_items: usize, invalid: &[usize]) -> Vec<usize> {
+        let items = create_items(num_items, invalid);
+        let mut valid = vec![true; items.len()];
+        find_invalid(&"foobar".hash::<Blake2bHash>(), &items, 0, &mut valid);
+        valid.iter().enumerate()
+            .filter(|&(_, &valid)| !valid)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_batch() {
+        assert_eq!(invalid_indices(8, &[]), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_one_invalid() {
+        assert_eq!(invalid_indices(8, &[5]), vec![5]);
+    }
+
+    #[test]
+    fn test_several_invalid() {
+        assert_eq!(invalid_indices(7, &[0, 3, 4, 6]), vec![0, 3, 4, 6]);
+    }
+}
@@ -1,26 +1,90 @@
 use std::sync::Arc;
 use std::time::Duration;
 
+#[cfg(test)]
+use hash::Hash;
 use hash::Blake2bHash;
 use bls::bls12_381::{KeyPair, Signature};
+use collections::bitset::BitSet;
 
-use crate::handel::Identity;
+use crate::handel::{
+    Identity, IdentityRegistry, BoxVerifier, Boxed, ThreadPoolVerifier, DummyVerifier, BatchVerifier,
+    SessionId, ThresholdKey, Contribution,
+};
+
+
+/// Backend that is used to verify signatures
+#[derive(Clone, Debug)]
+pub enum VerifierBackend {
+    /// Verify signatures on a thread pool. With `None` one worker per CPU is spawned.
+    ThreadPool { num_workers: Option<usize> },
+
+    /// Verify signatures in batches on a thread pool. A batch is verified when it has
+    /// `batch_size` signatures, or `batch_delay` after its first signature arrived.
+    Batch { num_workers: Option<usize>, batch_size: usize, batch_delay: Duration },
+
+    /// Accept every signature without checking it. Only use this for simulations!
+    Dummy,
+}
+
+impl Default for VerifierBackend {
+    fn default() -> Self {
+        VerifierBackend::ThreadPool { num_workers: None }
+    }
+}
 
 
 #[derive(Clone, Debug)]
 pub struct Config {
-    /// Number of signatures needed to consider the multisig valid
+    /// Minimum total weight of the signers needed to consider the multisig valid. A multisig
+    /// whose weight equals the threshold is valid.
     pub threshold: usize,
 
-    /// Hash of the message that is being signed
+    /// Hash of the message that is being signed, i.e. the value we vote for
     pub message_hash: Blake2bHash,
 
-    /// The identity of this node
+    /// Whether contributions for other values than `message_hash` are aggregated too. Otherwise
+    /// they're ignored.
+    pub multi_value: bool,
+
+    /// The session this aggregation belongs to. Messages for other sessions are ignored.
+    pub session: SessionId,
+
+    /// The identity of this node. Observers take the position of this identity in the
+    /// partitioning, but don't act on its behalf.
     pub node_identity: Arc<Identity>,
 
+    /// Whether we observe the aggregation instead of taking part in it. Observers aren't in the
+    /// committee. They pull the aggregates from the committee with catch-up requests, combine
+    /// them into the final signature, and never sign.
+    pub observer: bool,
+
+    /// Whether observers relay their aggregates to the peers of their position, like a member that
+    /// abstains. Otherwise observers never send contributions.
+    pub relay: bool,
+
+    /// With a threshold key the key pairs are shares of it. Contributions carry the partial
+    /// signatures that the sender knows, and the final signature must contain the signature of the
+    /// group, that is interpolated from them. The key must pass `ThresholdKey::check` for the
+    /// committee.
+    pub threshold_key: Option<ThresholdKey>,
+
+    /// Restricts our peers to these validators, e.g. to the representatives of the
+    /// sub-committees in the second tier of hierarchical Handel. With `None` every validator is a
+    /// peer.
+    pub peers: Option<BitSet>,
+
+    /// Our contribution instead of our individual signature, e.g. the aggregate of our
+    /// sub-committee in the second tier of hierarchical Handel
+    pub contribution: Option<Contribution>,
+
     /// Whether to disable shuffling of identities per level
     pub disable_shuffling: bool,
 
+    /// Seed for shuffling the identities per level. The protocol is deterministic for a given
+    /// seed.
+    pub seed: [u8; 32],
+
     /// Number of peers contacted during an update at each level
     pub update_count: usize,
 
@@ -33,12 +97,124 @@ pub struct Config {
     /// How many peers are contacted at each level ???
     pub peer_count: usize,
 
-    /// Key pair for signing the message
-    pub key_pair: KeyPair,
+    /// Whether to immediately send our aggregate for a level to new peers, once it is complete.
+    /// It's sent to `peer_count` peers in any case.
+    pub fast_path: bool,
+
+    /// Number of peers that we didn't contact yet, that are contacted through the fast path
+    pub fast_path_count: usize,
+
+    /// Whether to start all levels immediately and ask peers for their best aggregates. This is
+    /// meant for nodes that start late or restart during an aggregation.
+    pub catch_up: bool,
+
+    /// Number of invalid contributions after which a peer is blacklisted for the rest of the
+    /// session
+    pub blacklist_threshold: usize,
+
+    /// Initial size of the verification window of each level
+    pub window_initial: usize,
+
+    /// Minimum size of the verification windows
+    pub window_min: usize,
+
+    /// Maximum size of the verification windows
+    pub window_max: usize,
+
+    /// Maximum number of unverified contributions that are kept per level. If more arrive, the
+    /// ones with the worst score are dropped.
+    pub max_todos: usize,
+
+    /// The agent stops after this time, even if it didn't produce a final signature. The final
+    /// signature future then resolves with the best partial result.
+    pub deadline: Option<Duration>,
+
+    /// After reaching the threshold, keep improving the signature for this long, or until all
+    /// identities signed. With `None` the signature that reached the threshold is final.
+    pub extra_time: Option<Duration>,
+
+    /// After producing the final signature, keep helping peers that are still behind for this
+    /// long, or until all peers are done. With `None` the agent stops immediately.
+    pub linger: Option<Duration>,
+
+    /// Key pair for signing the message. Without a key pair we abstain: we don't sign, but still
+    /// aggregate and relay the contributions of our peers.
+    pub key_pair: Option<KeyPair>,
+
+    /// Backend used to verify incoming signatures
+    pub verifier: VerifierBackend,
+
+    /// Must be set to allow the `Dummy` verifier backend. This makes sure that a node doesn't
+    /// accept unverified signatures by accident.
+    pub allow_dummy_verifier: bool,
 }
 
 impl Config {
-    pub fn individual_signature(&self) -> Signature {
-        self.key_pair.sign_hash(self.message_hash.clone())
+    /// Our signature of the message, if we sign it
+    pub fn individual_signature(&self) -> Option<Signature> {
+        if self.observer || self.contribution.is_some() {
+            return None;
+        }
+        self.key_pair.as_ref()
+            .map(|key_pair| key_pair.sign_hash(self.message_hash.clone()))
+    }
+
+    /// Creates the verifier for the configured backend
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `Dummy` backend is configured, but `allow_dummy_verifier` is not set.
+    pub fn create_verifier(&self, identities: Arc<IdentityRegistry>) -> BoxVerifier {
+        match self.verifier {
+            VerifierBackend::ThreadPool { num_workers } => {
+                Boxed::boxed(ThreadPoolVerifier::new(self.threshold, identities, num_workers))
+            },
+            VerifierBackend::Batch { num_workers, batch_size, batch_delay } => {
+                Boxed::boxed(BatchVerifier::new(self.threshold, identities, num_workers, batch_size, batch_delay))
+            },
+            VerifierBackend::Dummy => {
+                assert!(self.allow_dummy_verifier, "Dummy verifier is configured, but not allowed");
+                warn!("Using dummy verifier. Signatures will not be checked!");
+                Boxed::boxed(DummyVerifier::new(self.threshold, identities))
+            },
+        }
+    }
+
+    /// Configuration of the node `id` for tests. It signs "foobar" with `key_pair`, doesn't
+    /// verify signatures and shuffles with a fixed seed.
+    #[cfg(test)]
+    pub fn for_test(identities: &IdentityRegistry, id: usize, key_pair: KeyPair, threshold: usize) -> Self {
+        Config {
+            threshold,
+            message_hash: "foobar".hash::<Blake2bHash>(),
+            multi_value: false,
+            session: 0,
+            node_identity: identities.get_by_id(id).unwrap(),
+            observer: false,
+            relay: false,
+            threshold_key: None,
+            peers: None,
+            contribution: None,
+            disable_shuffling: false,
+            seed: [42; 32],
+            update_count: 1,
+            update_period: Duration::from_millis(100),
+            timeout: Duration::from_millis(500),
+            peer_count: 10,
+            fast_path: true,
+            fast_path_count: 10,
+            catch_up: false,
+            blacklist_threshold: 1,
+            window_initial: 16,
+            window_min: 1,
+            window_max: 128,
+            max_todos: 64,
+            deadline: None,
+            extra_time: None,
+            linger: None,
+            key_pair: Some(key_pair),
+            verifier: VerifierBackend::Dummy,
+            allow_dummy_verifier: true,
+        }
     }
-}
\ No newline at end of file
+}
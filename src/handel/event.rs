@@ -0,0 +1,127 @@
+use futures::sync::mpsc::{unbounded, UnboundedSender, UnboundedReceiver};
+use parking_lot::Mutex;
+
+use crate::handel::{VerifyResult, FinalSignature, Evidence};
+
+
+/// Progress of an agent
+#[derive(Clone, Debug)]
+pub enum AgentEvent {
+    /// A level was started by its timeout
+    LevelStarted { level: usize },
+
+    /// We received the complete signature for a level
+    LevelCompleted { level: usize },
+
+    /// A contribution from `peer` was verified
+    ContributionVerified { peer: usize, level: usize },
+
+    /// A contribution from `peer` was rejected
+    ContributionRejected { peer: usize, level: usize, reason: VerifyResult },
+
+    /// A peer misbehaved
+    MisbehaviourDetected { evidence: Evidence },
+
+    /// The best signature at a level improved
+    SignatureImproved { level: usize, weight: usize },
+
+    /// The combined signature reached the threshold
+    ThresholdReached { signature: FinalSignature },
+
+    /// The combined signature can't be improved anymore, or the extra time to improve it is over
+    MaximalReached { signature: FinalSignature },
+
+    /// The agent stopped. This is the last event.
+    Stopped,
+}
+
+
+/// Sends items to all subscribers
+pub struct Broadcaster<T: Clone> {
+    subscribers: Mutex<Vec<UnboundedSender<T>>>,
+}
+
+impl<T: Clone> Broadcaster<T> {
+    pub fn new() -> Self {
+        Self {
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn subscribe(&self) -> UnboundedReceiver<T> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.lock().push(sender);
+        receiver
+    }
+
+    /// Subscribes and immediately sends `initial` to the new subscriber
+    pub fn subscribe_with(&self, initial: T) -> UnboundedReceiver<T> {
+        let (sender, receiver) = unbounded();
+        // the receiver can't be dropped yet
+        sender.unbounded_send(initial).unwrap_or(());
+        self.subscribers.lock().push(sender);
+        receiver
+    }
+
+    /// Sends `item` to all subscribers. Subscribers that dropped their stream are removed.
+    pub fn emit(&self, item: T) {
+        self.subscribers.lock()
+            .retain(|subscriber| subscriber.unbounded_send(item.clone()).is_ok());
+    }
+
+    /// Ends the streams of all subscribers
+    pub fn close(&self) {
+        self.subscribers.lock().clear();
+    }
+}
+
+impl<T: Clone> Default for Broadcaster<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// Sends events to all subscribers
+pub type EventBroadcaster = Broadcaster<AgentEvent>;
+
+
+#[cfg(test)]
+mod tests {
+    use futures::{Future, Stream};
+
+    use super::Broadcaster;
+
+    #[test]
+    fn test_multiple_subscribers() {
+        let broadcaster = Broadcaster::new();
+        let first = broadcaster.subscribe();
+        broadcaster.emit(1);
+
+        // late subscribers only get the items after they subscribed
+        let second = broadcaster.subscribe();
+        let third = broadcaster.subscribe_with(0);
+        broadcaster.emit(2);
+        broadcaster.emit(3);
+
+        // closing ends all streams
+        broadcaster.close();
+        assert_eq!(first.collect().wait().unwrap(), vec![1, 2, 3]);
+        assert_eq!(second.collect().wait().unwrap(), vec![2, 3]);
+        assert_eq!(third.collect().wait().unwrap(), vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn test_dropped_subscriber() {
+        let broadcaster = Broadcaster::new();
+        let dropped = broadcaster.subscribe();
+        let kept = broadcaster.subscribe();
+
+        drop(dropped);
+        broadcaster.emit(1);
+        assert_eq!(broadcaster.subscribers.lock().len(), 1, "Dropped subscriber was not removed");
+
+        broadcaster.close();
+        assert_eq!(kept.collect().wait().unwrap(), vec![1]);
+    }
+}
@@ -0,0 +1,171 @@
+use beserial::{Serialize, Deserialize};
+
+use crate::handel::{Message, IdentityRegistry, BinomialPartitioner};
+
+
+/// How a peer misbehaved
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum Misbehaviour {
+    /// The individual signature doesn't verify against the origin's public key
+    InvalidIndividual = 1,
+
+    /// The multi-signature contains signers outside of the partition that the origin aggregates
+    /// at the claimed level
+    SignersOutsidePartition = 2,
+}
+
+
+/// Evidence that a peer misbehaved
+///
+/// The evidence contains the complete message as it was received, so that it can be checked by
+/// anyone who knows the identities of the session, e.g. for slashing. The message is only
+/// attributable to its origin, if the origin signed it. So evidence must be checked with `verify`
+/// before it's used.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Evidence {
+    pub reason: Misbehaviour,
+
+    /// The message that proves the misbehaviour
+    pub message: Message,
+}
+
+impl Evidence {
+    pub fn new(message: Message, reason: Misbehaviour) -> Self {
+        Self {
+            reason,
+            message,
+        }
+    }
+
+    /// Checks that the message was signed by its origin and that it proves the misbehaviour of
+    /// the origin
+    pub fn verify(&self, identities: &IdentityRegistry) -> bool {
+        let origin = self.message.origin as usize;
+        let level = self.message.level as usize;
+
+        let identity = match identities.get_by_id(origin) {
+            Some(identity) => identity,
+            None => return false,
+        };
+
+        // without the origin's signature, anyone could have made up the message
+        match &self.message.origin_signature {
+            Some(signature) if identity.public_key.verify_hash(self.message.signed_hash(), signature) => {},
+            _ => return false,
+        }
+
+        match self.reason {
+            Misbehaviour::InvalidIndividual => {
+                match &self.message.individual {
+                    Some(individual) => !identity.public_key.verify_hash(self.message.value.clone(), individual),
+                    None => false,
+                }
+            },
+            Misbehaviour::SignersOutsidePartition => {
+                // At `level` the origin sends the aggregate of all its lower levels. A validator
+                // belongs to the partition that contains its first slot.
+                let partitioner = BinomialPartitioner::new(origin, identities.num_slots().saturating_sub(1));
+                if level == 0 || level >= partitioner.num_levels {
+                    return false;
+                }
+
+                match identities.signers(&self.message.multisig.signers) {
+                    Ok(signers) => signers.iter().any(|identity| {
+                        !(0 .. level).any(|lower| {
+                            partitioner.range(lower)
+                                .map(|range| range.contains(&identity.id))
+                                .unwrap_or(false)
+                        })
+                    }),
+                    // unknown signers don't prove anything about the partition
+                    Err(_) => false,
+                }
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use beserial::{Serialize, Deserialize};
+    use hash::{Hash, Blake2bHash};
+    use bls::bls12_381::KeyPair;
+
+    use crate::handel::{Message, MultiSignature};
+    use crate::handel::testing::{create_key_pairs, create_identities};
+    use super::{Evidence, Misbehaviour};
+
+    /// Creates a message of `origin`, that is signed by `key_pair`, and whose individual signature
+    /// signs `signed`
+    fn create_message(key_pair: &KeyPair, origin: usize, level: usize, signed: &str) -> Message {
+        let individual = key_pair.sign_hash(signed.hash::<Blake2bHash>());
+        let mut message = Message {
+            session: 1,
+            value: "foobar".hash::<Blake2bHash>(),
+            origin: origin as u16,
+            level: level as u8,
+            multisig: MultiSignature::from_individual(&individual, origin),
+            individual: Some(individual),
+            partials: Vec::new(),
+            done: false,
+            origin_signature: None,
+        };
+        message.origin_signature = Some(key_pair.sign_hash(message.signed_hash()));
+        message
+    }
+
+    #[test]
+    fn test_invalid_individual() {
+        let key_pairs = create_key_pairs(4);
+        let identities = create_identities(&key_pairs);
+
+        let evidence = Evidence::new(create_message(&key_pairs[2], 2, 1, "barfoo"), Misbehaviour::InvalidIndividual);
+        assert!(evidence.verify(&identities));
+
+        // survives serialization
+        let evidence = Evidence::deserialize_from_vec(&evidence.serialize_to_vec()).unwrap();
+        assert_eq!(evidence.reason, Misbehaviour::InvalidIndividual);
+        assert!(evidence.verify(&identities));
+
+        // a valid signature is no evidence
+        let evidence = Evidence::new(create_message(&key_pairs[2], 2, 1, "foobar"), Misbehaviour::InvalidIndividual);
+        assert!(!evidence.verify(&identities));
+    }
+
+    #[test]
+    fn test_unsigned_message() {
+        let key_pairs = create_key_pairs(4);
+        let identities = create_identities(&key_pairs);
+
+        // node 1 can't make up evidence against node 2
+        let forged = create_message(&key_pairs[1], 2, 1, "barfoo");
+        assert!(!Evidence::new(forged, Misbehaviour::InvalidIndividual).verify(&identities));
+
+        let mut unsigned = create_message(&key_pairs[2], 2, 1, "barfoo");
+        unsigned.origin_signature = None;
+        assert!(!Evidence::new(unsigned, Misbehaviour::InvalidIndividual).verify(&identities));
+
+        // the signature covers the whole message
+        let mut modified = create_message(&key_pairs[2], 2, 1, "barfoo");
+        modified.level = 2;
+        assert!(!Evidence::new(modified, Misbehaviour::InvalidIndividual).verify(&identities));
+    }
+
+    #[test]
+    fn test_signers_outside_partition() {
+        let key_pairs = create_key_pairs(4);
+        let identities = create_identities(&key_pairs);
+
+        // at level 1, node 2 only sends its own signature
+        let mut message = create_message(&key_pairs[2], 2, 1, "foobar");
+        assert!(!Evidence::new(message.clone(), Misbehaviour::SignersOutsidePartition).verify(&identities));
+
+        // node 0 is in a different partition
+        let other = key_pairs[0].sign_hash("foobar".hash::<Blake2bHash>());
+        message.multisig.add_individual(&other, 0).unwrap();
+        message.origin_signature = Some(key_pairs[2].sign_hash(message.signed_hash()));
+        assert!(Evidence::new(message, Misbehaviour::SignersOutsidePartition).verify(&identities));
+    }
+}
@@ -0,0 +1,366 @@
+use std::sync::Arc;
+use std::ops::RangeInclusive;
+use std::io::Error as IoError;
+use std::net::SocketAddr;
+
+use futures::Future;
+use parking_lot::RwLock;
+
+use collections::bitset::BitSet;
+
+use crate::handel::{
+    Config, Identity, IdentityRegistry, BinomialPartitioner, MultiSignature, SessionId, HandelAgent,
+    Handler, Message, CatchUpRequest, Packet, PacketBuffer,
+};
+
+
+/// The session of the second tier is the session of the first tier plus this offset. Sessions of
+/// the first tier must be below it, so that the sessions of both tiers never collide.
+pub const SECOND_TIER_OFFSET: SessionId = 1 << 31;
+
+
+/// The aggregate of a sub-committee, that its representative contributes to the second tier of
+/// hierarchical Handel
+#[derive(Clone, Debug)]
+pub struct Contribution {
+    pub multisig: MultiSignature,
+
+    /// Number of our levels that the contribution covers, i.e. the levels of our sub-committee
+    pub levels: usize,
+}
+
+
+/// Splits a committee into sub-committees for hierarchical Handel
+///
+/// A sub-committee consists of the levels `0 .. levels` of the `BinomialPartitioner` of each of
+/// its members, i.e. a contiguous range of `2^(levels - 1)` slots. Handel first runs inside every
+/// sub-committee. Then the representative of every sub-committee, which is its member with the
+/// lowest ID, contributes the aggregate of its sub-committee to a second Handel between the
+/// representatives. The second tier runs over the upper levels of the same partitioning, so the
+/// aggregates of the sub-committees never overlap and are combined with
+/// `MultiSignature::add_multisig`.
+#[derive(Clone, Debug)]
+pub struct Hierarchy {
+    /// Number of levels of a sub-committee
+    pub levels: usize,
+
+    /// All identities of the committee
+    identities: Arc<IdentityRegistry>,
+}
+
+impl Hierarchy {
+    pub fn new(identities: Arc<IdentityRegistry>, levels: usize) -> Self {
+        assert!(levels > 0, "A sub-committee needs at least one level");
+        assert!(identities.num_slots() > 0, "No identities");
+
+        Self {
+            levels,
+            identities,
+        }
+    }
+
+    /// The slots of the sub-committee of the validator `id`
+    pub fn sub_committee_range(&self, id: usize) -> RangeInclusive<usize> {
+        let partitioner = BinomialPartitioner::new(id, self.identities.num_slots() - 1);
+
+        let ranges = (0 .. self.levels.min(partitioner.num_levels))
+            .filter_map(|level| partitioner.range(level).ok())
+            .collect::<Vec<RangeInclusive<usize>>>();
+        let min = ranges.iter().map(|range| *range.start()).min().unwrap_or(id);
+        let max = ranges.iter().map(|range| *range.end()).max().unwrap_or(id);
+
+        min ..= max
+    }
+
+    /// The members of the sub-committee of the validator `id`
+    pub fn sub_committee(&self, id: usize) -> IdentityRegistry {
+        let range = self.sub_committee_range(id);
+
+        let mut registry = IdentityRegistry::new();
+        for identity in self.identities.get_by_id_range(*range.start(), *range.end() + 1) {
+            // the identities are valid in the whole committee, so they are in a part of it
+            registry.insert(identity).unwrap();
+        }
+        registry
+    }
+
+    /// The representative of the sub-committee of the validator `id`
+    pub fn representative(&self, id: usize) -> Option<Arc<Identity>> {
+        let range = self.sub_committee_range(id);
+        self.identities.get_by_id_range(*range.start(), *range.end() + 1)
+            .into_iter()
+            .next()
+    }
+
+    pub fn is_representative(&self, id: usize) -> bool {
+        self.representative(id)
+            .map(|representative| representative.id == id)
+            .unwrap_or(false)
+    }
+
+    /// The representatives of all sub-committees
+    pub fn representatives(&self) -> BitSet {
+        let mut representatives = BitSet::new();
+        for identity in self.identities.all() {
+            if self.is_representative(identity.id) {
+                representatives.insert(identity.id);
+            }
+        }
+        representatives
+    }
+
+    /// The configuration and identities of the first tier, i.e. of the aggregation inside the
+    /// sub-committee of `config.node_identity`. The threshold is the sub-committee's share of
+    /// `config.threshold`.
+    ///
+    /// NOTE: The partitioning of the first tier still spans the slots `0 ..= max_id` of the
+    /// sub-committee, so the levels with the slots below the sub-committee are empty.
+    pub fn first_tier(&self, config: &Config) -> (Config, IdentityRegistry) {
+        let sub_committee = self.sub_committee(config.node_identity.id);
+        let weight = |identities: &IdentityRegistry| -> usize {
+            identities.all().iter()
+                .map(|identity| identity.weight)
+                .sum()
+        };
+        let total_weight = weight(&self.identities);
+        let sub_committee_weight = weight(&sub_committee);
+
+        let mut config = config.clone();
+        // round up, so that the sub-committees together reach the threshold
+        config.threshold = (config.threshold * sub_committee_weight + total_weight - 1) / total_weight;
+
+        (config, sub_committee)
+    }
+
+    /// The session of the second tier, i.e. the session of `config` plus `SECOND_TIER_OFFSET`.
+    /// Returns `None`, if it overflows.
+    pub fn second_tier_session(&self, config: &Config) -> Option<SessionId> {
+        config.session.checked_add(SECOND_TIER_OFFSET)
+    }
+
+    /// The configuration of the second tier, in which our sub-committee is represented by
+    /// `multisig`. Returns `None`, if the session of the second tier overflows. This must only be
+    /// used by representatives, with all identities of the committee.
+    pub fn second_tier(&self, config: &Config, multisig: MultiSignature) -> Option<Config> {
+        let session = self.second_tier_session(config)?;
+        let mut config = config.clone();
+        config.session = session;
+        config.peers = Some(self.representatives());
+        config.contribution = Some(Contribution {
+            multisig,
+            levels: self.levels,
+        });
+        Some(config)
+    }
+}
+
+
+/// Routes the packets of both tiers of a node to their agents, so that the tiers share a network
+pub struct TierRouter {
+    first: Arc<HandelAgent>,
+
+    /// The agent of the second tier. It's set once the first tier is finished.
+    second: RwLock<Option<Arc<HandelAgent>>>,
+
+    /// Packets of the second tier, that arrive before its agent is set
+    buffer: PacketBuffer,
+}
+
+impl TierRouter {
+    /// Creates a router for the agent of the first tier. The packets of `second_session` are
+    /// buffered until the agent of the second tier is set.
+    pub fn new(first: Arc<HandelAgent>, second_session: Option<SessionId>) -> Self {
+        let buffer = PacketBuffer::new();
+        if let Some(session) = second_session {
+            buffer.expect(session);
+        }
+
+        Self {
+            first,
+            second: RwLock::new(None),
+            buffer,
+        }
+    }
+
+    /// Sets the agent of the second tier and passes the buffered packets to it.
+    ///
+    /// This must be called from within a tokio runtime, since the packets are handled on it.
+    pub fn set_second(&self, agent: Arc<HandelAgent>) {
+        *self.second.write() = Some(Arc::clone(&agent));
+        self.buffer.release(agent.session(), &agent);
+    }
+
+    fn agent(&self, session: SessionId) -> Option<Arc<HandelAgent>> {
+        if session == self.first.session() {
+            Some(Arc::clone(&self.first))
+        }
+        else {
+            self.second.read().as_ref()
+                .filter(|agent| agent.session() == session)
+                .cloned()
+        }
+    }
+}
+
+impl Handler for Arc<TierRouter> {
+    fn on_message(&self, message: Message, sender_address: SocketAddr) -> Box<dyn Future<Item=(), Error=IoError> + Send> {
+        if let Some(agent) = self.agent(message.session) {
+            agent.on_message(message, sender_address)
+        }
+        else {
+            // the second tier might not have started yet
+            let session = message.session;
+            self.buffer.buffer(session, Packet::Contribution(message), sender_address, || self.agent(session))
+        }
+    }
+
+    fn on_request(&self, request: CatchUpRequest, sender_address: SocketAddr) -> Box<dyn Future<Item=(), Error=IoError> + Send> {
+        if let Some(agent) = self.agent(request.session) {
+            agent.on_request(request, sender_address)
+        }
+        else {
+            let session = request.session;
+            self.buffer.buffer(session, Packet::CatchUp(request), sender_address, || self.agent(session))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::collections::{BTreeMap, VecDeque};
+
+    use hash::{Hash, Blake2bHash};
+
+    use crate::handel::{
+        Config, VerifyResult, Packet, FinalSignature, Protocol, Input, Output, Verification,
+        MultiSignature,
+    };
+    use crate::handel::testing::{address, create_key_pairs, create_identities};
+    use super::{Hierarchy, SECOND_TIER_OFFSET};
+
+    /// Routes the outputs of the protocols, which are indexed by node ID, in memory and drives
+    /// their timers, until all of them produced a result. Returns the successful results.
+    fn run(protocols: &mut BTreeMap<usize, Protocol>) -> BTreeMap<usize, FinalSignature> {
+        let num_levels = protocols.values().map(|protocol| protocol.num_levels()).max().unwrap();
+
+        let mut inputs = protocols.keys()
+            .map(|&id| (id, Input::Start))
+            .collect::<VecDeque<(usize, Input)>>();
+        let mut results = BTreeMap::new();
+
+        for round in 0 .. 32 {
+            while let Some((id, input)) = inputs.pop_front() {
+                for output in protocols.get_mut(&id).unwrap().handle(input) {
+                    match output {
+                        Output::Send { to, packet: Packet::Contribution(message) } if protocols.contains_key(&to) => {
+                            inputs.push_back((to, Input::Received { message, from: address(id) }))
+                        },
+                        Output::Verify { id: verification_id, verification } => {
+                            // every signature is valid and every signer has weight 1
+                            let votes = match verification {
+                                Verification::Individual { .. } => 1,
+                                Verification::Multisig { signature, .. } => signature.len(),
+                            };
+                            inputs.push_back((id, Input::Verified { id: verification_id, result: VerifyResult::Ok { votes } }));
+                        },
+                        Output::Result(Ok(signature)) => {
+                            results.insert(id, signature);
+                        },
+                        _ => {},
+                    }
+                }
+            }
+
+            if results.len() == protocols.len() {
+                break;
+            }
+
+            // timers
+            for &id in protocols.keys() {
+                if round < num_levels {
+                    inputs.push_back((id, Input::Timeout { level: round }));
+                }
+                inputs.push_back((id, Input::Update));
+            }
+        }
+
+        results
+    }
+
+    #[test]
+    fn test_sub_committees() {
+        // sub-committees of 4 nodes
+        let hierarchy = Hierarchy::new(Arc::new(create_identities(&create_key_pairs(14))), 3);
+
+        assert_eq!(hierarchy.sub_committee_range(0), 0 ..= 3);
+        assert_eq!(hierarchy.sub_committee_range(6), 4 ..= 7);
+        assert_eq!(hierarchy.sub_committee_range(13), 12 ..= 13);
+        assert_eq!(hierarchy.sub_committee(9).len(), 4);
+
+        assert_eq!(hierarchy.representative(6).map(|identity| identity.id), Some(4));
+        assert_eq!(hierarchy.representatives().iter().collect::<Vec<usize>>(), vec![0, 4, 8, 12]);
+    }
+
+    #[test]
+    fn test_two_sub_committees() {
+        // two sub-committees of 4 nodes, that need 3 signatures each
+        let key_pairs = create_key_pairs(8);
+        let identities = Arc::new(create_identities(&key_pairs));
+        let hierarchy = Hierarchy::new(Arc::clone(&identities), 3);
+        let configs = key_pairs.into_iter().enumerate()
+            .map(|(id, key_pair)| {
+                let mut config = Config::for_test(&identities, id, key_pair, 6);
+                // keep helping peers that are behind
+                config.linger = Some(Duration::from_secs(1));
+                config
+            })
+            .collect::<Vec<Config>>();
+
+        // first tier in every sub-committee
+        let mut contributions = BTreeMap::new();
+        for members in &[0 .. 4, 4 .. 8] {
+            let mut protocols = members.clone()
+                .map(|id| {
+                    let (config, sub_committee) = hierarchy.first_tier(&configs[id]);
+                    assert_eq!(config.threshold, 3);
+                    (id, Protocol::new(config, Arc::new(sub_committee)))
+                })
+                .collect::<BTreeMap<usize, Protocol>>();
+
+            let results = run(&mut protocols);
+            assert_eq!(results.len(), 4, "First tier of nodes {:?} didn't finish", members);
+            let representative = members.start;
+            contributions.insert(representative, results[&representative].multisig.clone());
+        }
+
+        // second tier between the representatives
+        let mut protocols = contributions.into_iter()
+            .map(|(id, multisig)| {
+                let config = hierarchy.second_tier(&configs[id], multisig).unwrap();
+                assert_eq!(config.session, SECOND_TIER_OFFSET);
+                (id, Protocol::new(config, Arc::clone(&identities)))
+            })
+            .collect::<BTreeMap<usize, Protocol>>();
+
+        let results = run(&mut protocols);
+        assert_eq!(results.len(), 2, "Second tier didn't finish");
+        for signature in results.values() {
+            assert!(signature.weight >= 6, "Weight {} is below the threshold", signature.weight);
+        }
+    }
+
+    #[test]
+    fn test_second_tier_session_overflow() {
+        let key_pairs = create_key_pairs(8);
+        let identities = Arc::new(create_identities(&key_pairs));
+        let hierarchy = Hierarchy::new(Arc::clone(&identities), 3);
+        let individual = key_pairs[0].sign_hash("foobar".hash::<Blake2bHash>());
+
+        let mut config = Config::for_test(&identities, 0, key_pairs[0].clone(), 6);
+        config.session = SECOND_TIER_OFFSET;
+        assert!(hierarchy.second_tier(&config, MultiSignature::from_individual(&individual, 0)).is_none());
+    }
+}
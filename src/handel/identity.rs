@@ -2,17 +2,34 @@ use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
 use std::collections::{HashMap, BTreeMap};
 use std::sync::Arc;
 use std::convert::TryFrom;
+use std::ops::Range;
+
+use failure::Fail;
 
 use beserial::{Serialize, Deserialize, ReadBytesExt, WriteBytesExt, SerializingError, BigEndian};
 use bls::bls12_381::PublicKey;
+use collections::bitset::BitSet;
+
 
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum IdentityError {
+    #[fail(display = "Slots of identity {} overlap with other identities", _0)]
+    OverlappingSlots(usize),
+}
 
 
+/// A validator
+///
+/// Validators own a contiguous range of slots. Signer bitsets and the partitioning are defined
+/// over slots, so that a validator's signature counts for all its slots. A validator's slots may
+/// span several partitions, but it belongs to the partition that contains its first slot.
 #[derive(Clone, Debug)]
 pub struct Identity {
+    /// The validator's first slot. This also identifies the validator.
     pub id: usize,
     pub public_key: PublicKey,
     pub address: SocketAddr,
+    /// Number of slots, i.e. the validator owns the slots `id .. id + weight`
     pub weight: usize,
 }
 
@@ -25,12 +42,18 @@ impl Identity {
             weight
         }
     }
+
+    /// The slots of this validator
+    pub fn slots(&self) -> Range<usize> {
+        self.id .. self.id + self.weight
+    }
 }
 
 impl Serialize for Identity {
     fn serialize<W: WriteBytesExt>(&self, writer: &mut W) -> Result<usize, SerializingError> {
         let mut size = 2 /* id */ + 8 /* weight */;
-        writer.write_u16::<BigEndian>(self.id as u16)?;
+        writer.write_u16::<BigEndian>(u16::try_from(self.id)
+                             .map_err(|_| SerializingError::Overflow)?)?;
         size += Serialize::serialize(&self.public_key, writer)?;
         size += serialize_socket_addr(&self.address, writer)?;
         writer.write_u64::<BigEndian>(u64::try_from(self.weight)
@@ -39,7 +62,7 @@ impl Serialize for Identity {
     }
 
     fn serialized_size(&self) -> usize {
-        2 + self.public_key.serialized_size() + serialized_size_socket_addr(&self.address)
+        2 + self.public_key.serialized_size() + serialized_size_socket_addr(&self.address) + 8
     }
 }
 
@@ -130,9 +153,17 @@ impl IdentityRegistry {
         }
     }
 
-    pub fn insert(&mut self, identity: Arc<Identity>) {
+    /// Inserts a validator. Its slots must not overlap with the slots of other validators.
+    pub fn insert(&mut self, identity: Arc<Identity>) -> Result<(), IdentityError> {
+        // the slots can only overlap with the validator before it, or the ones that start
+        // within its slots
+        if self.get_by_slot(identity.id).is_some() || !self.get_by_id_range(identity.id, identity.id + identity.weight).is_empty() {
+            return Err(IdentityError::OverlappingSlots(identity.id));
+        }
+
         self.by_id.insert(identity.id, Arc::clone(&identity));
         self.by_address.insert(identity.address.clone(), identity);
+        Ok(())
     }
 
     pub fn get_by_id(&self, id: usize) -> Option<Arc<Identity>> {
@@ -140,6 +171,21 @@ impl IdentityRegistry {
             .map(|identity| Arc::clone(identity))
     }
 
+    /// Returns the validator that owns `slot`
+    pub fn get_by_slot(&self, slot: usize) -> Option<Arc<Identity>> {
+        self.by_id.range(..= slot)
+            .next_back()
+            .filter(|(_, identity)| slot < identity.id + identity.weight)
+            .map(|(_, identity)| Arc::clone(identity))
+    }
+
+    /// Returns the validator with this public key
+    pub fn get_by_public_key(&self, public_key: &PublicKey) -> Option<Arc<Identity>> {
+        self.by_id.values()
+            .find(|identity| identity.public_key == *public_key)
+            .map(|identity| Arc::clone(identity))
+    }
+
     pub fn get_by_id_range(&self, min: usize, max: usize) -> Vec<Arc<Identity>> {
         let mut identities: Vec<Arc<Identity>> = Vec::new();
         for (_, identity) in self.by_id.range(min..max) {
@@ -157,6 +203,48 @@ impl IdentityRegistry {
         self.by_id.len()
     }
 
+    /// Total number of slots
+    pub fn num_slots(&self) -> usize {
+        self.by_id.values()
+            .map(|identity| identity.id + identity.weight)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Total weight of the slots in `slots`. Slots that don't belong to any validator don't add
+    /// any weight.
+    pub fn weight(&self, slots: &BitSet) -> usize {
+        slots.iter()
+            .filter(|&slot| self.get_by_slot(slot).is_some())
+            .count()
+    }
+
+    /// Returns the validators that signed for `slots`. A validator must have signed for all of its
+    /// slots. Otherwise the first slot that doesn't belong to a complete validator is returned as
+    /// error.
+    pub fn signers(&self, slots: &BitSet) -> Result<Vec<Arc<Identity>>, usize> {
+        let mut signers = Vec::new();
+        // all slots before this one are covered by a signer
+        let mut next_slot = 0;
+
+        for slot in slots.iter() {
+            if slot < next_slot {
+                continue;
+            }
+
+            // the slot must be the first slot of a validator
+            let identity = self.by_id.get(&slot).ok_or(slot)?;
+            if let Some(missing) = identity.slots().find(|&slot| !slots.contains(slot)) {
+                return Err(missing);
+            }
+
+            next_slot = identity.id + identity.weight;
+            signers.push(Arc::clone(identity));
+        }
+
+        Ok(signers)
+    }
+
     pub fn all(&self) -> Vec<Arc<Identity>> {
         let mut identities: Vec<Arc<Identity>> = Vec::new();
         for (_, identity) in self.by_id.iter() {
@@ -171,3 +259,54 @@ impl Default for IdentityRegistry {
         IdentityRegistry::new()
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
+
+    use beserial::{Serialize, Deserialize};
+    use bls::bls12_381::KeyPair;
+
+    use crate::handel::testing::address;
+    use super::{Identity, IdentityRegistry, IdentityError};
+
+    fn create_identity(id: usize, weight: usize) -> Arc<Identity> {
+        let mut csprng = ChaChaRng::from_seed([42; 32]);
+        Arc::new(Identity::new(id, KeyPair::generate(&mut csprng).public, address(id), weight))
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut registry = IdentityRegistry::new();
+        registry.insert(create_identity(0, 2)).unwrap();
+        registry.insert(create_identity(4, 4)).unwrap();
+
+        assert_eq!(registry.insert(create_identity(1, 1)), Err(IdentityError::OverlappingSlots(1)));
+        assert_eq!(registry.insert(create_identity(6, 2)), Err(IdentityError::OverlappingSlots(6)));
+        assert_eq!(registry.insert(create_identity(2, 4)), Err(IdentityError::OverlappingSlots(2)));
+
+        // the slots may span partitions
+        registry.insert(create_identity(8, 3)).unwrap();
+        registry.insert(create_identity(2, 1)).unwrap();
+        assert_eq!(registry.num_slots(), 11);
+    }
+
+    #[test]
+    fn test_serialization() {
+        let identity = create_identity(4, 4);
+        let serialized = identity.serialize_to_vec();
+        assert_eq!(serialized.len(), identity.serialized_size());
+
+        let deserialized = Identity::deserialize_from_vec(&serialized).unwrap();
+        assert_eq!(deserialized.id, 4);
+        assert_eq!(deserialized.weight, 4);
+        assert_eq!(deserialized.address, identity.address);
+
+        // the ID doesn't fit the wire format
+        assert!(create_identity(1 << 16, 1).serialize(&mut Vec::new()).is_err());
+    }
+}
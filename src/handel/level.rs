@@ -1,10 +1,11 @@
-use std::cmp::min;
 use std::sync::Arc;
+use std::collections::BTreeMap;
 
-use rand::thread_rng;
-use parking_lot::RwLock;
+use rand::SeedableRng;
+use rand_chacha::ChaChaRng;
+use collections::bitset::BitSet;
 
-use crate::handel::{MultiSignature, BinomialPartitioner, PartitioningError, Config};
+use crate::handel::{BinomialPartitioner, PartitioningError, Config, VerificationWindow, IdentityRegistry};
 use rand::seq::SliceRandom;
 
 
@@ -15,29 +16,52 @@ pub struct LevelState {
     pub send_peers_pos: usize,
     pub send_signature_size: usize,
     pub send_peers_count: usize,
+    /// Position of the next peer to ask for a catch-up
+    pub catch_up_pos: usize,
+    /// Whether we already sent our complete aggregate for this level via the fast path
+    pub fast_path_sent: bool,
+    pub window: VerificationWindow,
+    /// Peers whose contributions we verified at this level
+    pub verified: BitSet,
+    /// Peers that we sent to at this level
+    pub contacted: BitSet,
 }
 
 #[derive(Debug)]
 pub struct Level {
     pub id: usize,
     pub peer_ids: Vec<usize>,
+    /// Rank of each peer, i.e. its position in `peer_ids`
+    /// ID -> rank
+    ranks: BTreeMap<usize, usize>,
     pub send_expected_full_size: usize,
-    pub state: RwLock<LevelState>
+    pub state: LevelState,
 }
 
 impl Level {
-    pub fn new(id: usize, peer_ids: Vec<usize>, send_expected_full_size: usize) -> Level {
+    pub fn new(id: usize, peer_ids: Vec<usize>, send_expected_full_size: usize, window: VerificationWindow) -> Level {
+        let ranks = peer_ids.iter()
+            .enumerate()
+            .map(|(rank, &id)| (id, rank))
+            .collect();
+
         Level {
             id,
             peer_ids,
+            ranks,
             send_expected_full_size,
-            state: RwLock::new(LevelState {
+            state: LevelState {
                 send_started: false,
                 receive_completed: false,
                 send_peers_pos: 0,
                 send_signature_size: 0,
                 send_peers_count: 0,
-            })
+                catch_up_pos: 0,
+                fast_path_sent: false,
+                window,
+                verified: BitSet::new(),
+                contacted: BitSet::new(),
+            },
         }
     }
 
@@ -45,11 +69,30 @@ impl Level {
         self.peer_ids.len()
     }
 
-    pub fn create_levels(config: &Config, partitioner: Arc<BinomialPartitioner>) -> Vec<Level> {
+    /// The rank of a peer at this level. Contributions from peers with a lower rank are verified
+    /// first.
+    pub fn rank(&self, peer_id: usize) -> Option<usize> {
+        self.ranks.get(&peer_id).cloned()
+    }
+
+    /// The rank at which the verification window starts, i.e. the best rank whose contribution we
+    /// didn't verify yet. Blacklisted peers are skipped, since we won't verify them anymore.
+    pub fn window_start(&self, blacklist: &BitSet) -> usize {
+        self.peer_ids.iter()
+            .position(|&id| !self.state.verified.contains(id) && !blacklist.contains(id))
+            .unwrap_or_else(|| self.peer_ids.len())
+    }
+
+    /// Creates the levels. The partitioner partitions the slots and a level contains the
+    /// validators whose first slot is in the level's range. Only the validators in
+    /// `Config::peers` are peers, but the levels' sizes include all validators.
+    pub fn create_levels(config: &Config, partitioner: Arc<BinomialPartitioner>, identities: &IdentityRegistry) -> Vec<Level> {
         let mut levels: Vec<Level> = Vec::new();
         let mut first_active = false;
-        let mut send_expected_full_size: usize = 1;
-        let mut rng = thread_rng();
+        // number of slots of the complete signature we send at a level, i.e. of all lower levels
+        let mut send_expected_full_size: usize = 0;
+        let mut rng = ChaChaRng::from_seed(config.seed);
+        let window = VerificationWindow::new(config.window_initial, config.window_min, config.window_max);
 
         for i in 0 .. partitioner.num_levels {
             debug!("Creating level {}", i);
@@ -57,26 +100,35 @@ impl Level {
             // This unwrap is safe, since we only iterate until `num_levels - 1`
             match partitioner.range(i) {
                 Ok(ids) => {
-                    let mut ids = ids.collect::<Vec<usize>>();
+                    let ids = ids.filter(|&id| identities.get_by_id(id).is_some())
+                        .collect::<Vec<usize>>();
 
                     debug!("Number of identities: {}", ids.len());
+                    let size: usize = ids.iter()
+                        .filter_map(|&id| identities.get_by_id(id))
+                        .map(|identity| identity.weight)
+                        .sum();
+
+                    let mut ids = match &config.peers {
+                        Some(peers) => ids.into_iter().filter(|&id| peers.contains(id)).collect(),
+                        None => ids,
+                    };
                     if !config.disable_shuffling {
                         ids.shuffle(&mut rng);
                     }
 
-                    let size = ids.len();
-                    let level = Level::new(i, ids, send_expected_full_size);
+                    let mut level = Level::new(i, ids, send_expected_full_size, window.clone());
 
                     if !first_active {
                         first_active = true;
-                        level.state.write().send_started = true;
+                        level.state.send_started = true;
                     }
 
                     levels.push(level);
                     send_expected_full_size += size;
                 },
                 Err(PartitioningError::EmptyLevel(_)) => {
-                    let level = Level::new(i, vec![], send_expected_full_size);
+                    let level = Level::new(i, vec![], send_expected_full_size, window.clone());
                     levels.push(level);
                 },
                 Err(e) => panic!("{}", e),
@@ -87,35 +139,81 @@ impl Level {
     }
 
     pub fn active(&self) -> bool {
-        let state = self.state.read();
-        state.send_started && state.send_peers_count < self.peer_ids.len()
+        self.state.send_started && self.state.send_peers_count < self.peer_ids.len()
+    }
+
+    /// Selects the next `count` peers to send to. Blacklisted peers are skipped.
+    pub fn select_next_peers(&mut self, count: usize, blacklist: &BitSet) -> Vec<usize> {
+        let state = &mut self.state;
+        let selected = Self::rotate(&self.peer_ids, &mut state.send_peers_pos, count, blacklist);
+
+        for &id in &selected {
+            state.contacted.insert(id);
+        }
+
+        selected
+    }
+
+    /// Selects the next `count` peers to ask for a catch-up. They're rotated separately from the
+    /// peers that we send to, and don't count as contacted. Blacklisted peers are skipped.
+    pub fn select_catch_up_peers(&mut self, count: usize, blacklist: &BitSet) -> Vec<usize> {
+        Self::rotate(&self.peer_ids, &mut self.state.catch_up_pos, count, blacklist)
     }
 
-    pub fn select_next_peers(&self, count: usize) -> Vec<usize> {
-        let size = min(count, self.peer_ids.len());
+    /// Selects the next `count` peers from `peer_ids`, starting at `pos`, which is advanced past
+    /// the selected peers
+    fn rotate(peer_ids: &[usize], pos: &mut usize, count: usize, blacklist: &BitSet) -> Vec<usize> {
         let mut selected: Vec<usize> = Vec::new();
 
-        let mut state = self.state.write();
-        for _ in 0..size {
-            // NOTE: Unwrap is safe, since we make sure at least `size` elements are in `self.peers`
-            selected.push(*self.peer_ids.get(state.send_peers_pos).unwrap());
-            state.send_peers_pos += 1;
-            if state.send_peers_pos >= self.peer_ids.len() {
-                state.send_peers_pos = 0;
+        // visit every peer at most once
+        for _ in 0..peer_ids.len() {
+            if selected.len() >= count {
+                break;
+            }
+
+            // NOTE: Index is safe, since we wrap `pos` around at the end of `peer_ids`
+            let id = peer_ids[*pos];
+            *pos += 1;
+            if *pos >= peer_ids.len() {
+                *pos = 0;
             }
+
+            if !blacklist.contains(id) {
+                selected.push(id);
+            }
+        }
+
+        selected
+    }
+
+    /// Selects up to `count` peers that we didn't send to yet, in the order of their rank.
+    /// Blacklisted peers are skipped.
+    pub fn select_new_peers(&mut self, count: usize, blacklist: &BitSet) -> Vec<usize> {
+        let state = &mut self.state;
+
+        let selected = self.peer_ids.iter()
+            .filter(|&&id| !state.contacted.contains(id) && !blacklist.contains(id))
+            .take(count)
+            .cloned()
+            .collect::<Vec<usize>>();
+
+        for &id in &selected {
+            state.contacted.insert(id);
         }
 
         selected
     }
 
-    pub fn update_signature_to_send(&self, signature: &MultiSignature) -> bool {
-        let mut state = self.state.write();
+    /// Updates the number of slots of the aggregates that we send at this level. Returns `true`,
+    /// if they just became complete.
+    pub fn update_signature_to_send(&mut self, size: usize) -> bool {
+        let state = &mut self.state;
 
-        if state.send_signature_size >= signature.len() {
+        if state.send_signature_size >= size {
             return false;
         }
 
-        state.send_signature_size = signature.len();
+        state.send_signature_size = size;
         state.send_peers_count = 0;
 
         if state.send_signature_size == self.send_expected_full_size {
@@ -126,7 +224,7 @@ impl Level {
         false
     }
 
-    pub fn start(&self) {
-        self.state.write().send_started = true;
+    pub fn start(&mut self) {
+        self.state.send_started = true;
     }
 }
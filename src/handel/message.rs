@@ -1,13 +1,139 @@
-use beserial::{Serialize, Deserialize};
+use std::io::Write;
+
+use beserial::{Serialize, Deserialize, ReadBytesExt, WriteBytesExt, SerializingError};
 use bls::bls12_381::Signature;
+use hash::{Blake2bHash, Blake2bHasher, Hasher};
+
+use crate::handel::{MultiSignature, PartialSignature};
 
-use crate::handel::MultiSignature;
 
+/// Identifies a Handel session, i.e. one aggregation of a specific message
+pub type SessionId = u32;
 
+
+/// A contribution to the aggregation
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Message {
+    pub session: SessionId,
+    /// Hash of the value that the signatures sign
+    pub value: Blake2bHash,
     pub origin: u16,
     pub level: u8,
     pub multisig: MultiSignature,
     pub individual: Option<Signature>,
+    /// Partial signatures of other signers of `multisig`, if a threshold key is used
+    #[beserial(len_type(u16))]
+    pub partials: Vec<PartialSignature>,
+    /// Whether the origin already produced its final signature
+    pub done: bool,
+    /// Signature of the origin over `signed_hash`. It proves that the origin sent the message, so
+    /// that the message can be used as evidence against it.
+    pub origin_signature: Option<Signature>,
+}
+
+/// Prefix of the hash that the origin of a message signs. The origin signs values with the same
+/// key, so the prefix makes sure that a signed message can't be passed off as a vote for a value.
+const SIGNED_MESSAGE_PREFIX: &[u8] = b"handel-message";
+
+impl Message {
+    /// The hash of the message without `origin_signature`, which is signed by the origin
+    pub fn signed_hash(&self) -> Blake2bHash {
+        let mut message = self.clone();
+        message.origin_signature = None;
+
+        let mut hasher = Blake2bHasher::new();
+        hasher.write_all(SIGNED_MESSAGE_PREFIX).expect("Failed to write prefix to hasher");
+        Serialize::serialize(&message, &mut hasher).expect("Failed to write message to hasher");
+        hasher.finish()
+    }
+}
+
+
+/// Asks a peer for its best aggregates for the levels `min_level ..= max_level`. This is used by
+/// nodes that start late to catch up, and by observers.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatchUpRequest {
+    pub session: SessionId,
+    pub origin: u16,
+    pub min_level: u8,
+    pub max_level: u8,
+    /// Whether the request comes from an observer that takes the position of `origin`. The
+    /// answer is sent to the address of the request instead of `origin`.
+    pub observer: bool,
+}
+
+
+/// Everything that is sent over the network
+#[derive(Clone, Debug)]
+pub enum Packet {
+    Contribution(Message),
+    CatchUp(CatchUpRequest),
+}
+
+const PACKET_CONTRIBUTION: u8 = 0;
+const PACKET_CATCH_UP: u8 = 1;
+
+impl Serialize for Packet {
+    fn serialize<W: WriteBytesExt>(&self, writer: &mut W) -> Result<usize, SerializingError> {
+        let size = match self {
+            Packet::Contribution(message) => {
+                writer.write_u8(PACKET_CONTRIBUTION)?;
+                Serialize::serialize(message, writer)?
+            },
+            Packet::CatchUp(request) => {
+                writer.write_u8(PACKET_CATCH_UP)?;
+                Serialize::serialize(request, writer)?
+            },
+        };
+        Ok(1 + size)
+    }
+
+    fn serialized_size(&self) -> usize {
+        1 + match self {
+            Packet::Contribution(message) => message.serialized_size(),
+            Packet::CatchUp(request) => request.serialized_size(),
+        }
+    }
+}
+
+impl Deserialize for Packet {
+    fn deserialize<R: ReadBytesExt>(reader: &mut R) -> Result<Self, SerializingError> {
+        match reader.read_u8()? {
+            PACKET_CONTRIBUTION => Ok(Packet::Contribution(Deserialize::deserialize(reader)?)),
+            PACKET_CATCH_UP => Ok(Packet::CatchUp(Deserialize::deserialize(reader)?)),
+            _ => Err(SerializingError::InvalidEncoding),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use beserial::{Serialize, Deserialize};
+
+    use super::{Packet, CatchUpRequest};
+
+    #[test]
+    fn test_catch_up_roundtrip() {
+        let request = CatchUpRequest {
+            session: 42,
+            origin: 7,
+            min_level: 1,
+            max_level: 4,
+            observer: true,
+        };
+
+        let raw = Packet::CatchUp(request.clone()).serialize_to_vec();
+        assert_eq!(raw.len(), Packet::CatchUp(request.clone()).serialized_size());
+
+        match Packet::deserialize_from_vec(&raw).unwrap() {
+            Packet::CatchUp(decoded) => assert_eq!(decoded, request),
+            packet => panic!("Unexpected packet: {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn test_invalid_packet_type() {
+        assert!(Packet::deserialize_from_vec(&[0xff, 0, 0]).is_err());
+    }
 }
@@ -11,16 +11,44 @@ pub mod utils;
 mod store;
 mod verifier;
 mod timeout;
+mod session;
+mod shutdown;
+mod window;
+mod todo;
+mod batch;
+mod event;
+mod result;
+mod protocol;
+mod pbft;
+mod evidence;
+mod aggregate;
+mod threshold;
+mod hierarchy;
+#[cfg(test)]
+mod testing;
 
 
 pub use level::Level;
-pub use message::Message;
-pub use identity::{Identity, IdentityRegistry};
+pub use message::{Message, SessionId, CatchUpRequest, Packet};
+pub use identity::{Identity, IdentityRegistry, IdentityError};
 pub use multisig::MultiSignature;
-pub use agent::{HandelAgent, AgentProcessor};
-pub use config::Config;
+pub use agent::{HandelAgent, AgentProcessor, AgentHandle};
+pub use config::{Config, VerifierBackend};
 pub use partitioner::{BinomialPartitioner, PartitioningError};
 pub use network::{UdpNetwork, Handler};
 pub use store::{SignatureStore, ReplaceStore};
-pub use verifier::{ThreadPoolVerifier, VerifyResult, DummyVerifier, Verifier};
+pub use verifier::{ThreadPoolVerifier, VerifyResult, DummyVerifier, Verifier, VerifyFuture, BoxVerifier, Boxed};
 pub use timeout::{TimeoutStrategy, LinearTimeout};
+pub use session::{SessionManager, SessionResolver, PacketBuffer};
+pub use shutdown::{Shutdown, ShutdownSignal};
+pub use window::VerificationWindow;
+pub use todo::{Todo, TodoQueue};
+pub use batch::BatchVerifier;
+pub use event::{AgentEvent, Broadcaster, EventBroadcaster};
+pub use result::{FinalSignature, HandelResult, AggregationError, PartialResult, LevelCompletion, ImprovedSignature};
+pub use protocol::{Protocol, Input, Output, Verification, VerificationId};
+pub use pbft::{PbftPhase, PbftError, pbft_proof, pbft_aggregate};
+pub use evidence::{Evidence, Misbehaviour};
+pub use aggregate::{aggregate, observe, AggregateParams, AggregateError};
+pub use threshold::{ThresholdKey, ThresholdSetup, ThresholdError, PartialSignature, MAX_THRESHOLD};
+pub use hierarchy::{Hierarchy, Contribution, TierRouter, SECOND_TIER_OFFSET};
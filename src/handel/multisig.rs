@@ -1,3 +1,5 @@
+use std::ops::Range;
+
 use failure::Fail;
 
 use beserial::{Serialize, Deserialize};
@@ -41,6 +43,22 @@ impl MultiSignature {
         }
     }
 
+    /// Creates a multi-signature from a validator's signature, that counts for all its `slots`
+    pub fn from_slots(signature: &Signature, slots: Range<usize>) -> MultiSignature {
+        let mut aggregate = AggregateSignature::new();
+        let mut signers = BitSet::new();
+
+        aggregate.aggregate(&signature);
+        for slot in slots {
+            signers.insert(slot);
+        }
+
+        MultiSignature {
+            signature: aggregate,
+            signers,
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.signers.len()
     }
@@ -70,5 +88,19 @@ impl MultiSignature {
         }
     }
 
+    /// Adds a validator's signature that counts for all its `slots`
+    pub fn add_slots(&mut self, other: &Signature, slots: Range<usize>) -> Result<(), MultiSigError> {
+        if let Some(slot) = slots.clone().find(|&slot| self.signers.contains(slot)) {
+            Err(MultiSigError::Contained(slot))
+        }
+        else {
+            self.signature.aggregate(other);
+            for slot in slots {
+                self.signers.insert(slot);
+            }
+            Ok(())
+        }
+    }
+
     // TODO: verify, etc.
 }
@@ -6,13 +6,13 @@ use tokio::net::{UdpSocket, UdpFramed};
 use tokio::io::Error as IoError;
 use tokio::codec::{Encoder, Decoder};
 use bytes::{BytesMut, BufMut};
-use futures::{Stream, Future, Sink, future, IntoFuture};
+use futures::{Stream, Future, Sink, future};
 use futures::sync::mpsc::{unbounded, UnboundedSender, UnboundedReceiver};
 use parking_lot::RwLock;
 
 use beserial::{Serialize, Deserialize, WriteBytesExt, ReadBytesExt, BigEndian};
 
-use crate::handel::Message;
+use crate::handel::{Message, CatchUpRequest, Packet, ShutdownSignal};
 use rand::{thread_rng, Rng};
 
 
@@ -45,20 +45,21 @@ impl Statistics {
 
 pub trait Handler {
     fn on_message(&self, message: Message, sender_address: SocketAddr) -> Box<dyn Future<Item=(), Error=IoError> + Send>;
+    fn on_request(&self, request: CatchUpRequest, sender_address: SocketAddr) -> Box<dyn Future<Item=(), Error=IoError> + Send>;
 }
 
 
 pub struct UdpNetwork {
     pub statistics: Arc<RwLock<Statistics>>,
-    sender: UnboundedSender<(Message, SocketAddr)>,
-    receiver: Option<UnboundedReceiver<(Message, SocketAddr)>>,
+    sender: UnboundedSender<(Packet, SocketAddr)>,
+    receiver: Option<UnboundedReceiver<(Packet, SocketAddr)>>,
 }
 
 type UdpNetworkFuture = Box<dyn Future<Item=(), Error=()> + Send>;
 
 impl UdpNetwork {
     pub fn new() -> Self {
-        let (sender, receiver) = unbounded::<(Message, SocketAddr)>();
+        let (sender, receiver) = unbounded::<(Packet, SocketAddr)>();
         Self {
             statistics: Arc::new(RwLock::new(Statistics::default())),
             sender,
@@ -66,7 +67,9 @@ impl UdpNetwork {
         }
     }
 
-    pub fn connect<H: Handler + Send + 'static>(&mut self, bind_to: &SocketAddr, handler: H) -> Result<UdpNetworkFuture, IoError> {
+    /// Binds the UDP socket and creates the future that sends and receives messages. The future
+    /// finishes when `shutdown` is triggered, which also closes the socket.
+    pub fn connect<H: Handler + Send + 'static>(&mut self, bind_to: &SocketAddr, handler: H, shutdown: ShutdownSignal) -> Result<UdpNetworkFuture, IoError> {
         // set up UDP socket
         let socket = UdpSocket::bind(bind_to)?;
         let framed = UdpFramed::new(socket, Codec::new(Arc::clone(&self.statistics)));
@@ -81,23 +84,26 @@ impl UdpNetwork {
                     })
                 );
 
-                let buf_spawn = tokio::spawn(buf_fut.map(|(_sink, _source)| {
+                let send = shutdown.guard(buf_fut.map(|(_sink, _source)| {
                     warn!("Buffer thread finished");
                 }).map_err(|e| {
                     error!("Send buffer failed: {}", e);
                 }));
 
-                let recv_spawn = tokio::spawn(stream.for_each(move |(message, sender_address)| {
-                    //debug!("Received from {}: {:?}", sender_address, message);
-                    handler.on_message(message, sender_address)
+                let recv = shutdown.guard(stream.for_each(move |(packet, sender_address)| {
+                    //debug!("Received from {}: {:?}", sender_address, packet);
+                    match packet {
+                        Packet::Contribution(message) => handler.on_message(message, sender_address),
+                        Packet::CatchUp(request) => handler.on_request(request, sender_address),
+                    }
                 }).or_else(|e| {
                     error!("Receive stream error: {}", e);
                     future::ok(())
                 }));
 
-                buf_spawn.into_future()
-                    .join(recv_spawn.into_future())
-                    .map(|_| ()) // join returns ((), ()), so map it to ()
+                // when both finished, the socket is dropped
+                send.join(recv)
+                    .map(|_| debug!("Network closed")) // join returns ((), ()), so map it to ()
             })))
         }
         else {
@@ -105,7 +111,7 @@ impl UdpNetwork {
         }
     }
 
-    pub fn sink(&self) -> UnboundedSender<(Message, SocketAddr)> {
+    pub fn sink(&self) -> UnboundedSender<(Packet, SocketAddr)> {
         self.sender.clone()
     }
 }
@@ -126,7 +132,7 @@ impl Codec {
 }
 
 impl Encoder for Codec {
-    type Item = Message;
+    type Item = Packet;
     type Error = IoError;
 
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
@@ -151,7 +157,7 @@ impl Encoder for Codec {
 }
 
 impl Decoder for Codec {
-    type Item = Message;
+    type Item = Packet;
     type Error = IoError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
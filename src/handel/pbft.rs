@@ -0,0 +1,172 @@
+use std::sync::Arc;
+
+use failure::Fail;
+use futures::{Future, future};
+
+use block::{MacroHeader, PbftPrepareMessage, PbftCommitMessage, PbftProof};
+use block::signed::{AggregateProof, Message as SignedMessage};
+use bls::bls12_381::KeyPair;
+use hash::{Hash, Blake2bHash};
+
+use crate::handel::{
+    SessionManager, SessionId, MultiSignature, HandelResult, AggregationError, IdentityRegistry,
+    AggregateParams, AggregateError, UdpNetwork, Shutdown,
+};
+
+
+#[derive(Debug, Fail)]
+pub enum PbftError {
+    #[fail(display = "Setup failed: {}", _0)]
+    Setup(#[cause] AggregateError),
+    #[fail(display = "Prepare phase failed: {}", _0)]
+    Prepare(AggregationError),
+    #[fail(display = "Commit phase failed: {}", _0)]
+    Commit(AggregationError),
+    #[fail(display = "Agent of session {} was dropped", _0)]
+    Canceled(SessionId),
+    #[fail(display = "Session ID of block #{} overflows", _0)]
+    SessionOverflow(u32),
+}
+
+
+/// Phases of pBFT for which signatures are aggregated
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PbftPhase {
+    Prepare,
+    Commit,
+}
+
+impl PbftPhase {
+    /// The Handel session of this phase for a block. Every block has one session per phase.
+    pub fn session(self, header: &MacroHeader) -> Result<SessionId, PbftError> {
+        let offset = match self {
+            PbftPhase::Prepare => 0,
+            PbftPhase::Commit => 1,
+        };
+        header.block_number.checked_mul(2)
+            .and_then(|session| session.checked_add(offset))
+            .ok_or(PbftError::SessionOverflow(header.block_number))
+    }
+
+    /// The hash that is signed in this phase for a block
+    pub fn message_hash(self, header: &MacroHeader) -> Blake2bHash {
+        let block_hash = header.hash::<Blake2bHash>();
+        match self {
+            PbftPhase::Prepare => PbftPrepareMessage { block_hash }.hash_with_prefix(),
+            PbftPhase::Commit => PbftCommitMessage { block_hash }.hash_with_prefix(),
+        }
+    }
+}
+
+
+/// Runs Handel for the pBFT prepare phase of a macro block and then for its commit phase. The
+/// resulting signatures are converted into the proof of the macro block.
+///
+/// Both phases run as sessions of `sessions`. The commit phase is only started once we have the
+/// prepare signature, thus `sessions` must not resolve sessions on demand. Packets of peers that
+/// started the commit phase earlier are buffered until then. This must be called from within a
+/// tokio runtime.
+pub fn pbft_proof(sessions: &Arc<SessionManager>, header: MacroHeader) -> Box<dyn Future<Item=PbftProof, Error=PbftError> + Send> {
+    let sessions = Arc::clone(sessions);
+
+    let (prepare_session, commit_session) = match (PbftPhase::Prepare.session(&header), PbftPhase::Commit.session(&header)) {
+        (Ok(prepare), Ok(commit)) => (prepare, commit),
+        (Err(e), _) | (_, Err(e)) => return Box::new(future::err(e)),
+    };
+    sessions.expect_session(commit_session);
+
+    let prepare = run_phase(&sessions, &header, PbftPhase::Prepare, prepare_session)
+        .map_err(PbftError::from_phase(PbftPhase::Prepare));
+
+    Box::new(prepare.and_then(move |prepare| {
+        info!("Prepare phase of block #{} finished with {} signers", header.block_number, prepare.len());
+        run_phase(&sessions, &header, PbftPhase::Commit, commit_session)
+            .map_err(PbftError::from_phase(PbftPhase::Commit))
+            .map(move |commit| {
+                info!("Commit phase of block #{} finished with {} signers", header.block_number, commit.len());
+                PbftProof {
+                    prepare: AggregateProof::new(prepare.signature, prepare.signers),
+                    commit: AggregateProof::new(commit.signature, commit.signers),
+                }
+            })
+    }))
+}
+
+/// Runs both pBFT phases for a macro block with `committee` and returns the proof of the block
+///
+/// This is the pBFT counterpart of `aggregate`: It sets up the network and the sessions, and tears
+/// them down once the agents of both phases stopped. It must be polled from within a tokio
+/// runtime.
+pub fn pbft_aggregate(committee: IdentityRegistry, key_pair: KeyPair, header: MacroHeader, params: AggregateParams) -> Box<dyn Future<Item=PbftProof, Error=PbftError> + Send> {
+    let start = future::lazy(move || -> Result<(Arc<SessionManager>, Shutdown), PbftError> {
+        let node_identity = committee.get_by_public_key(&key_pair.public)
+            .ok_or(PbftError::Setup(AggregateError::NotInCommittee))?;
+        let bind_to = params.bind_address(&node_identity);
+        // the message hash is set per session
+        let config = params.config(&committee, node_identity, Some(key_pair), Blake2bHash::default());
+        if let Some(threshold_key) = &config.threshold_key {
+            threshold_key.check(&committee)
+                .map_err(|e| PbftError::Setup(AggregateError::ThresholdKey(e)))?;
+        }
+
+        let mut network = UdpNetwork::new();
+        let sessions = Arc::new(SessionManager::new(config, committee, network.sink(), params.deadline * 2));
+        let shutdown = Shutdown::new();
+
+        let network_fut = network.connect(&bind_to, Arc::clone(&sessions), shutdown.signal())
+            .map_err(|e| PbftError::Setup(AggregateError::Network(e)))?;
+        tokio::spawn(network_fut);
+
+        // stops the agents that outlive the session timeout
+        let gc_fut = SessionManager::garbage_collector(&sessions, params.deadline);
+        tokio::spawn(shutdown.signal().guard(gc_fut));
+
+        Ok((sessions, shutdown))
+    });
+
+    Box::new(start.and_then(move |(sessions, shutdown)| {
+        pbft_proof(&sessions, header.clone())
+            .then(move |result| {
+                // keep the network up while the agents help their peers
+                let stopped = [PbftPhase::Prepare, PbftPhase::Commit].iter()
+                    .filter_map(|phase| phase.session(&header).ok())
+                    .filter_map(|session| sessions.session(session))
+                    .map(|agent| agent.shutdown_signal())
+                    .collect::<Vec<_>>();
+
+                future::join_all(stopped)
+                    .then(move |_| {
+                        shutdown.trigger();
+                        result
+                    })
+            })
+    }))
+}
+
+/// Starts the session for a phase and resolves with its maximal signature
+fn run_phase(sessions: &SessionManager, header: &MacroHeader, phase: PbftPhase, session: SessionId) -> Box<dyn Future<Item=MultiSignature, Error=Result<AggregationError, SessionId>> + Send> {
+    let agent = sessions.start_session(session, phase.message_hash(header));
+
+    match agent.maximal_signature() {
+        Some(result) => Box::new(result
+            .map_err(move |_| Err(session))
+            .and_then(|result: HandelResult| {
+                result.map(|signature| signature.multisig)
+                    .map_err(Ok)
+            })),
+        None => {
+            error!("Result of session {} was taken already", session);
+            Box::new(future::err(Err(session)))
+        },
+    }
+}
+
+impl PbftError {
+    fn from_phase(phase: PbftPhase) -> impl Fn(Result<AggregationError, SessionId>) -> PbftError {
+        move |e| match (e, phase) {
+            (Ok(e), PbftPhase::Prepare) => PbftError::Prepare(e),
+            (Ok(e), PbftPhase::Commit) => PbftError::Commit(e),
+            (Err(session), _) => PbftError::Canceled(session),
+        }
+    }
+}
@@ -0,0 +1,1521 @@
+use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use bls::bls12_381::Signature;
+use collections::bitset::BitSet;
+use hash::Blake2bHash;
+
+use crate::handel::{
+    IdentityRegistry, Message, CatchUpRequest, Packet, Config, BinomialPartitioner, Level,
+    MultiSignature, SignatureStore, ReplaceStore, VerifyResult, SessionId, Todo, TodoQueue,
+    AgentEvent, FinalSignature, HandelResult, AggregationError, PartialResult, LevelCompletion,
+    Evidence, Misbehaviour, PartialSignature,
+};
+
+
+/// Identifies a verification that was requested by the protocol
+pub type VerificationId = usize;
+
+
+/// A signature that must be verified. `value` is the hash of the message that it signs.
+#[derive(Clone, Debug)]
+pub enum Verification {
+    Individual { value: Blake2bHash, signature: Signature, signer: usize },
+    Multisig { value: Blake2bHash, signature: MultiSignature },
+}
+
+
+/// Inputs to the protocol
+#[derive(Clone, Debug)]
+pub enum Input {
+    /// Starts the aggregation. This must be the first input.
+    Start,
+
+    /// A contribution was received from the address `from`
+    Received { message: Message, from: SocketAddr },
+
+    /// A peer asked for our aggregates. `from` is the address that the request came from.
+    CatchUpRequested { request: CatchUpRequest, from: SocketAddr },
+
+    /// The timeout for a level passed
+    Timeout { level: usize },
+
+    /// Periodic update
+    Update,
+
+    /// A verification that was requested with `Output::Verify` finished
+    Verified { id: VerificationId, result: VerifyResult },
+
+    /// The deadline passed
+    Deadline,
+
+    /// The extra time to improve the signature after reaching the threshold is over
+    ExtraTimeOver,
+
+    /// The linger period is over
+    LingerOver,
+
+    /// Stops the aggregation
+    Stop,
+}
+
+
+/// Outputs of the protocol. They must be carried out in order.
+#[derive(Clone, Debug)]
+pub enum Output {
+    /// Send a packet to the peer with this ID
+    Send { to: usize, packet: Packet },
+
+    /// Send a packet to an address that doesn't belong to an identity, e.g. an observer
+    SendTo { address: SocketAddr, packet: Packet },
+
+    /// Verify a signature and pass the result back with `Input::Verified`
+    Verify { id: VerificationId, verification: Verification },
+
+    /// Progress of the aggregation
+    Event(AgentEvent),
+
+    /// The combined signature of all levels improved. This is output whenever its weight
+    /// increases, even if it's below the threshold.
+    Improved(FinalSignature),
+
+    /// The result of the aggregation, i.e. the first signature that reached the threshold. This
+    /// is output exactly once.
+    Result(HandelResult),
+
+    /// We reached the threshold, but keep improving the signature. `Input::ExtraTimeOver` should
+    /// be passed after `Config::extra_time`.
+    ExtraTime,
+
+    /// The maximal signature, i.e. the best signature once the extra time is over or all
+    /// identities signed. Without extra time this is the same as the result. This is output
+    /// exactly once, after `Result`.
+    MaximalResult(HandelResult),
+
+    /// We produced the final signature, but keep helping our peers. `Input::LingerOver` should
+    /// be passed after `Config::linger`.
+    Linger,
+
+    /// The protocol stopped. It won't produce any outputs anymore.
+    Stop,
+}
+
+
+/// The Handel protocol as a deterministic state machine
+///
+/// The protocol doesn't do any I/O. It's driven by passing `Input`s to `handle`, which returns
+/// the `Output`s that must be carried out. Timers, the network and signature verification are
+/// provided by a driver, e.g. `HandelAgent`.
+pub struct Protocol {
+    /// Handel configuration
+    config: Config,
+
+    /// All known identities
+    identities: Arc<IdentityRegistry>,
+
+    /// Partitions the slots into levels
+    partitioner: Arc<BinomialPartitioner>,
+
+    /// Levels
+    levels: Vec<Level>,
+
+    /// Verified signatures
+    store: ReplaceStore,
+
+    /// Contributions that weren't verified yet, per level, ordered by their score
+    pending: Vec<TodoQueue>,
+
+    /// Contributions that are being verified. Only one contribution per level is verified at a
+    /// time.
+    verifying: BTreeMap<VerificationId, Todo>,
+
+    /// ID of the next requested verification
+    next_verification: VerificationId,
+
+    /// Our individual signature. `None`, if we abstain or observe.
+    individual: Option<Signature>,
+
+    /// Number of invalid contributions per origin
+    misbehaviour: BTreeMap<usize, usize>,
+
+    /// Peers whose contributions we don't verify anymore and that we don't send to
+    blacklist: BitSet,
+
+    /// Evidence of misbehaving peers
+    evidence: Vec<Evidence>,
+
+    /// Our signatures of the last message that we sent per level and value, so that a message is
+    /// only signed again when it changed
+    /// (level, value) -> (signed hash, signature)
+    origin_signatures: BTreeMap<(usize, Blake2bHash), (Blake2bHash, Signature)>,
+
+    /// Peers that signaled that they're done
+    peers_done: BitSet,
+
+    /// Number of peers at all levels
+    num_peers: usize,
+
+    /// Total weight of all identities
+    total_weight: usize,
+
+    /// Weight of the last combined signature that was output as improved
+    best_weight: usize,
+
+    /// Whether we produced the final signature, i.e. the maximal one
+    done: bool,
+
+    /// Whether the result was output already
+    result_sent: bool,
+
+    /// Whether the maximal result was output already
+    maximal_sent: bool,
+
+    stopped: bool,
+
+    /// Outputs of the input that is currently handled
+    outputs: Vec<Output>,
+}
+
+impl Protocol {
+    pub fn new(config: Config, identities: Arc<IdentityRegistry>) -> Self {
+        // the partitioning is over slots
+        let num_slots = identities.num_slots();
+        assert!(num_slots > 0, "No identities");
+
+        // initialize EVERYTHING!
+        let partitioner = Arc::new(BinomialPartitioner::new(config.node_identity.id, num_slots - 1));
+        let levels = Level::create_levels(&config, Arc::clone(&partitioner), &identities);
+        let mut store = ReplaceStore::new(Arc::clone(&partitioner), Arc::clone(&identities));
+        let individual = config.individual_signature();
+        if individual.is_none() {
+            // we won't get a signature for our own level
+            store.set_optional(0);
+        }
+        if config.threshold_key.is_some() {
+            store.set_collect_individuals();
+        }
+        if let Some(contribution) = &config.contribution {
+            // the levels of our sub-committee are covered by our contribution
+            for level in 1 .. contribution.levels {
+                store.set_optional(level);
+            }
+        }
+        let total_weight = identities.all().iter()
+            .map(|identity| identity.weight)
+            .sum();
+        // level 0 contains our own position, which is only a peer for observers
+        let num_peers = levels.iter()
+            .flat_map(|level| level.peer_ids.iter())
+            .filter(|&&id| config.observer || id != config.node_identity.id)
+            .count();
+
+        // every message carries up to `threshold` partial signatures besides its multi-signature
+        // and individual signature, so the queues must have room for at least one message
+        let max_todos = match &config.threshold_key {
+            Some(threshold_key) => config.max_todos.max(threshold_key.threshold + 2),
+            None => config.max_todos,
+        };
+
+        Self {
+            pending: levels.iter().map(|level| TodoQueue::new(level.id, max_todos)).collect(),
+            config,
+            identities,
+            partitioner,
+            levels,
+            store,
+            verifying: BTreeMap::new(),
+            next_verification: 0,
+            individual,
+            misbehaviour: BTreeMap::new(),
+            blacklist: BitSet::new(),
+            evidence: Vec::new(),
+            origin_signatures: BTreeMap::new(),
+            peers_done: BitSet::new(),
+            num_peers,
+            total_weight,
+            best_weight: 0,
+            done: false,
+            result_sent: false,
+            maximal_sent: false,
+            stopped: false,
+            outputs: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn session(&self) -> SessionId {
+        self.config.session
+    }
+
+    pub fn num_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn store(&self) -> &ReplaceStore {
+        &self.store
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Peers that sent too many invalid contributions
+    pub fn blacklist(&self) -> &BitSet {
+        &self.blacklist
+    }
+
+    /// Evidence of all misbehaviour that we detected
+    pub fn evidence(&self) -> &[Evidence] {
+        &self.evidence
+    }
+
+    /// Handles an input and returns the outputs that must be carried out
+    pub fn handle(&mut self, input: Input) -> Vec<Output> {
+        if self.stopped {
+            return Vec::new();
+        }
+
+        match input {
+            Input::Start => self.on_start(),
+            Input::Received { message, from } => self.on_message(message, from),
+            Input::CatchUpRequested { request, from } => self.on_request(request, from),
+            Input::Timeout { level } => self.on_timeout(level),
+            Input::Update => self.on_update(),
+            Input::Verified { id, result } => self.on_verified(id, result),
+            Input::Deadline => self.on_deadline(),
+            Input::ExtraTimeOver => self.on_extra_time_over(),
+            Input::LingerOver => {
+                info!("Linger period is over");
+                self.stop();
+            },
+            Input::Stop => self.stop(),
+        }
+
+        std::mem::replace(&mut self.outputs, Vec::new())
+    }
+
+    /// The best combined signature and the completion of all levels
+    pub fn partial_result(&self) -> PartialResult {
+        // find the highest level for which we can combine a signature, and the value with the
+        // highest weight there
+        let best = self.store.values()
+            .filter_map(|value| {
+                (0 .. self.levels.len()).rev()
+                    .filter_map(|level| self.store.combined(value, level))
+                    .next()
+                    .map(|best| (value.clone(), best))
+            })
+            .max_by_key(|(_, best)| self.store.weight(best));
+        let weight = best.as_ref()
+            .map(|(_, best)| self.store.weight(best))
+            .unwrap_or(0);
+        let (value, best) = match best {
+            Some((value, best)) => (Some(value), Some(best)),
+            None => (None, None),
+        };
+
+        let levels = self.levels.iter()
+            .map(|level| LevelCompletion {
+                level: level.id,
+                weight: self.identities.weight(&self.store.signers(level.id)),
+                total_weight: self.store.level_weight(level.id),
+                complete: level.state.receive_completed,
+            })
+            .collect();
+
+        PartialResult {
+            value,
+            best,
+            weight,
+            levels,
+        }
+    }
+
+    fn emit(&mut self, event: AgentEvent) {
+        self.outputs.push(Output::Event(event));
+    }
+
+    fn finish(&mut self, result: HandelResult) {
+        if !self.result_sent {
+            self.result_sent = true;
+            self.outputs.push(Output::Result(result));
+        }
+    }
+
+    fn finish_maximal(&mut self, result: HandelResult) {
+        if !self.maximal_sent {
+            self.maximal_sent = true;
+            self.outputs.push(Output::MaximalResult(result));
+        }
+    }
+
+    /// The combined signatures of all values up to `level`
+    fn combined(&self, level: usize) -> Vec<(Blake2bHash, MultiSignature)> {
+        self.store.values()
+            .filter_map(|value| {
+                self.store.combined(value, level)
+                    .map(|multisig| (value.clone(), multisig))
+            })
+            .collect()
+    }
+
+    /// The combined signature of all levels for the value with the highest weight
+    fn best_signature(&self) -> Option<FinalSignature> {
+        self.combined(self.levels.len() - 1).into_iter()
+            .map(|(value, multisig)| {
+                let weight = self.store.weight(&multisig);
+                FinalSignature { value, multisig, weight, group_signature: None }
+            })
+            .max_by_key(|signature| signature.weight)
+    }
+
+    /// The combined signature of all levels, if it reaches the threshold. With a threshold key, we
+    /// also need enough partial signatures to interpolate the signature of the group.
+    fn final_signature(&self) -> Option<FinalSignature> {
+        let mut signature = self.best_signature()
+            .filter(|signature| signature.weight >= self.config.threshold)?;
+
+        if let Some(threshold_key) = &self.config.threshold_key {
+            let partials = self.store.individuals(&signature.value, 0 .. self.levels.len());
+            signature.group_signature = Some(threshold_key.combine(&partials)?);
+        }
+
+        Some(signature)
+    }
+
+    fn stop(&mut self) {
+        if self.stopped {
+            return;
+        }
+
+        info!("Stopping agent");
+        self.stopped = true;
+
+        // if we didn't produce a final signature yet, we won't anymore
+        if !self.result_sent {
+            let partial = self.partial_result();
+            self.finish(Err(AggregationError::Stopped(partial)));
+        }
+
+        // if we were still improving the signature, the best one we have is the maximal one
+        if !self.maximal_sent {
+            let result = match self.final_signature() {
+                Some(final_signature) => Ok(final_signature),
+                None => {
+                    let partial = self.partial_result();
+                    Err(AggregationError::Stopped(partial))
+                },
+            };
+            self.finish_maximal(result);
+        }
+
+        self.emit(AgentEvent::Stopped);
+        self.outputs.push(Output::Stop);
+    }
+
+    /// Called when the deadline is reached. If we didn't reach the threshold yet, the best
+    /// partial result is output as error.
+    fn on_deadline(&mut self) {
+        if !self.result_sent {
+            warn!("Deadline reached");
+            let partial = self.partial_result();
+            self.finish(Err(AggregationError::DeadlineExceeded(partial.clone())));
+            self.finish_maximal(Err(AggregationError::DeadlineExceeded(partial)));
+        }
+        self.stop();
+    }
+
+    /// Called when the extra time to improve the signature is over
+    fn on_extra_time_over(&mut self) {
+        if self.done {
+            return;
+        }
+
+        if let Some(final_signature) = self.final_signature() {
+            info!("Extra time is over");
+            self.complete(final_signature);
+        }
+    }
+
+    /// Puts our own individual signature into the store and sends it to level 0. Observers start
+    /// all levels and ask the committee for its aggregates instead.
+    fn on_start(&mut self) {
+        if self.config.observer {
+            info!("Observing");
+            self.start_all_levels();
+            self.send_catch_up_requests(self.config.peer_count);
+            return;
+        }
+
+        if self.config.contribution.is_some() {
+            self.contribute();
+            return;
+        }
+
+        match self.individual.clone() {
+            Some(individual) => {
+                let origin = self.config.node_identity.id;
+                let value = self.config.message_hash.clone();
+                let todo = Todo::Individual { value: value.clone(), signature: individual.clone(), level: 0, origin, message: None, authenticated: true };
+                todo.clone().put(&mut self.store);
+
+                // notify
+                self.check_completed_level(todo.level());
+                self.check_improved();
+                self.check_final_signature();
+
+                // send level 0
+                let multisig = MultiSignature::from_slots(&individual, self.config.node_identity.slots());
+                self.send_update(vec![(value, multisig)], 0, self.config.peer_count);
+            },
+            None => {
+                // we abstain, so there is nothing to receive at our own level
+                info!("Abstaining");
+                self.levels[0].state.receive_completed = true;
+                self.emit(AgentEvent::LevelCompleted { level: 0 });
+                if self.levels.len() > 1 {
+                    self.start_level(1);
+                }
+            },
+        }
+
+        // if we're late, don't wait for the timeouts, but start all levels and ask our peers what
+        // we missed
+        if self.config.catch_up {
+            info!("Catching up");
+            self.start_all_levels();
+            self.send_catch_up_requests(self.config.peer_count);
+        }
+    }
+
+    /// Puts our contribution into the store. It completes the levels that it covers, so we start
+    /// with the level above them.
+    fn contribute(&mut self) {
+        let contribution = match self.config.contribution.clone() {
+            Some(contribution) => contribution,
+            None => return,
+        };
+
+        let origin = self.config.node_identity.id;
+        let votes = self.store.weight(&contribution.multisig);
+        info!("Contributing signature with weight {}", votes);
+        Todo::Multi { value: self.config.message_hash.clone(), signature: contribution.multisig, level: 0, votes, origin, authenticated: true }
+            .put(&mut self.store);
+
+        let levels = contribution.levels.min(self.levels.len());
+        for level in 0 .. levels {
+            self.levels[level].state.receive_completed = true;
+            self.emit(AgentEvent::LevelCompleted { level });
+        }
+
+        self.check_improved();
+        self.check_final_signature();
+
+        if levels < self.levels.len() && !self.stopped {
+            self.start_level(levels);
+        }
+    }
+
+    fn start_all_levels(&mut self) {
+        for level in 1 .. self.levels.len() {
+            self.emit(AgentEvent::LevelStarted { level });
+            self.levels[level].start();
+        }
+    }
+
+    /// Whether `from` is the address of the validator `origin`. Anyone can claim any origin in a
+    /// message, so misbehaviour is only counted against the origin, if the message came from its
+    /// address.
+    fn is_authentic(&self, origin: usize, from: &SocketAddr) -> bool {
+        self.identities.get_by_id(origin)
+            .map(|identity| identity.address == *from)
+            .unwrap_or(false)
+    }
+
+    /// Records an invalid contribution from `origin` and blacklists it, if it misbehaved too often.
+    /// The contribution must have come from the address of `origin`.
+    fn report_invalid(&mut self, origin: usize) {
+        let count = {
+            let count = self.misbehaviour.entry(origin).or_insert(0);
+            *count += 1;
+            *count
+        };
+
+        if count >= self.config.blacklist_threshold && !self.blacklist.contains(origin) {
+            warn!("Blacklisting peer {} after {} invalid contributions", origin, count);
+            self.blacklist.insert(origin);
+        }
+    }
+
+    /// Records evidence that the origin of `message` misbehaved, if the origin signed the message.
+    /// It only counts against the origin, if the message was `authenticated` by its address.
+    fn accuse(&mut self, message: Message, reason: Misbehaviour, authenticated: bool) {
+        let origin = message.origin as usize;
+        warn!("Peer {} misbehaved: {:?}", origin, reason);
+
+        let evidence = Evidence::new(message, reason);
+        if evidence.verify(&self.identities) {
+            self.evidence.push(evidence.clone());
+            self.emit(AgentEvent::MisbehaviourDetected { evidence });
+        }
+        else {
+            debug!("Message of peer {} is no evidence", origin);
+        }
+
+        if authenticated {
+            self.report_invalid(origin);
+        }
+    }
+
+    /// Whether all signers of `multisig` are in the partition of `level`. A validator belongs to
+    /// the partition that contains its first slot.
+    fn within_partition(&self, multisig: &MultiSignature, level: usize) -> bool {
+        let range = match self.partitioner.range(level) {
+            Ok(range) => range,
+            Err(_) => return false,
+        };
+
+        match self.identities.signers(&multisig.signers) {
+            Ok(signers) => signers.iter().all(|identity| range.contains(&identity.id)),
+            // unknown signers are rejected by the verifier
+            Err(_) => true,
+        }
+    }
+
+    /// Our contribution for `value` at `level`. Our individual signature is only attached, if we
+    /// signed `value`.
+    fn message(&mut self, value: Blake2bHash, multisig: MultiSignature, individual: Option<Signature>, level: usize) -> Message {
+        let individual = if value == self.config.message_hash { individual } else { None };
+
+        // with a threshold key, we pass on the partial signatures of our lower levels
+        let partials = match &self.config.threshold_key {
+            Some(threshold_key) => {
+                let own_id = if individual.is_some() { Some(self.config.node_identity.id) } else { None };
+                self.store.individuals(&value, 0 .. level).into_iter()
+                    .filter(|&(id, _)| Some(id) != own_id)
+                    .take(threshold_key.threshold)
+                    .filter_map(|(id, signature)| PartialSignature::new(id, signature))
+                    .collect()
+            },
+            None => Vec::new(),
+        };
+
+        let mut message = Message {
+            session: self.config.session,
+            value,
+            origin: self.config.node_identity.id as u16,
+            level: level as u8,
+            multisig,
+            individual,
+            partials,
+            done: self.done,
+            origin_signature: None,
+        };
+
+        // sign the message, so that it can be used as evidence against us
+        if let Some(key_pair) = &self.config.key_pair {
+            let key = (level, message.value.clone());
+            let signed_hash = message.signed_hash();
+            let cached = self.origin_signatures.get(&key)
+                .filter(|(last_hash, _)| *last_hash == signed_hash)
+                .map(|(_, signature)| signature.clone());
+            let signature = match cached {
+                Some(signature) => signature,
+                None => {
+                    let signature = key_pair.sign_hash(signed_hash.clone());
+                    self.origin_signatures.insert(key, (signed_hash, signature.clone()));
+                    signature
+                },
+            };
+            message.origin_signature = Some(signature);
+        }
+
+        message
+    }
+
+    /// Sends one message per value to the peers `to`. Observers only send, if they relay.
+    fn send_to(&mut self, to: Vec<usize>, multisigs: Vec<(Blake2bHash, MultiSignature)>, individual: Option<Signature>, level: usize) {
+        if self.config.observer && !self.config.relay {
+            return;
+        }
+
+        for (value, multisig) in multisigs {
+            let message = self.message(value, multisig, individual.clone(), level);
+
+            //debug!("Sending to {:?}: {:?}", to, message);
+
+            for &id in &to {
+                if id == self.config.node_identity.id {
+                    continue;
+                }
+                self.outputs.push(Output::Send { to: id, packet: Packet::Contribution(message.clone()) });
+            }
+        }
+    }
+
+    /// Asks `count` peers at every level that isn't complete yet for their best aggregates. The
+    /// peers answer with our contribution for the respective level, as they would on a periodic
+    /// update.
+    fn send_catch_up_requests(&mut self, count: usize) {
+        let observer = self.config.observer;
+        // NOTE: Skip level 0, unless we observe. Then we ask the node whose position we take for
+        //       its individual signature.
+        let min_level = if observer { 0 } else { 1 };
+        let max_level = self.levels.len().saturating_sub(1);
+        let request = CatchUpRequest {
+            session: self.config.session,
+            origin: self.config.node_identity.id as u16,
+            min_level: min_level as u8,
+            max_level: max_level as u8,
+            observer,
+        };
+
+        for level in min_level .. self.levels.len() {
+            if self.levels[level].state.receive_completed {
+                continue;
+            }
+
+            let peer_ids = self.levels[level].select_catch_up_peers(count, &self.blacklist);
+            debug!("Requesting catch-up from {:?} at level {}", peer_ids, level);
+
+            for id in peer_ids {
+                if id != self.config.node_identity.id || observer {
+                    self.outputs.push(Output::Send { to: id, packet: Packet::CatchUp(request.clone()) });
+                }
+            }
+        }
+    }
+
+    fn on_timeout(&mut self, level: usize) {
+        self.emit(AgentEvent::LevelStarted { level });
+        self.start_level(level);
+    }
+
+    fn start_level(&mut self, level: usize) {
+        debug!("Starting level {}", level);
+
+        self.levels.get_mut(level)
+            .unwrap_or_else(|| panic!("Timeout for invalid level {}", level))
+            .start();
+
+        if level > 0 {
+            let multisigs = self.combined(level - 1);
+            if !multisigs.is_empty() {
+                self.send_update(multisigs, level, self.config.peer_count);
+            }
+        }
+    }
+
+    /// Periodic update: Sends our aggregates to the next peers of each level and verifies the
+    /// contributions that the verification windows admit now.
+    fn on_update(&mut self) {
+        // observers keep asking for the levels that they're missing
+        if self.config.observer {
+            self.send_catch_up_requests(self.config.update_count);
+        }
+
+        // NOTE: Skip level 0
+        for level in 1 .. self.levels.len() {
+            //debug!("send update for level {}", level);
+            let multisigs = self.combined(level - 1);
+            if !multisigs.is_empty() {
+                self.send_update(multisigs, level, self.config.update_count);
+            }
+        }
+
+        for level in 0 .. self.levels.len() {
+            self.verify_next(level);
+        }
+    }
+
+    fn check_completed_level(&mut self, level: usize) {
+        debug!("check_completed_level: {}", level);
+
+        if level >= self.levels.len() {
+            error!("Invalid level: {}", level);
+            return;
+        }
+
+        if self.levels[level].state.receive_completed {
+            debug!("check_completed_level: receive_completed=true");
+            return;
+        }
+
+        debug!("check_completed_level: level={}, weight={}, level.weight={}", level, self.identities.weight(&self.store.signers(level)), self.store.level_weight(level));
+
+        if self.store.is_complete(level) {
+            //info!("Level {} complete", level);
+            self.levels[level].state.receive_completed = true;
+            self.emit(AgentEvent::LevelCompleted { level });
+
+            if level + 1 < self.levels.len() {
+                // activate next level
+                self.start_level(level + 1)
+            }
+        }
+
+        for i in level + 1 .. self.levels.len() {
+            let multisigs = self.combined(i - 1);
+            if multisigs.is_empty() {
+                continue;
+            }
+
+            // the aggregates that we send at a level are complete, if they contain all slots of
+            // the lower levels, for any value
+            let mut signers = BitSet::new();
+            for (_, multisig) in &multisigs {
+                signers = &signers | &multisig.signers;
+            }
+
+            if self.levels[i].update_signature_to_send(signers.len()) {
+                self.send_update(multisigs.clone(), i, self.config.peer_count);
+                if self.config.fast_path {
+                    self.send_fast_path(multisigs, i);
+                }
+            }
+        }
+    }
+
+    /// Outputs the combined signature of all levels, if it's better than the last one
+    fn check_improved(&mut self) {
+        if let Some(signature) = self.best_signature() {
+            if signature.weight > self.best_weight {
+                self.best_weight = signature.weight;
+                self.outputs.push(Output::Improved(signature));
+            }
+        }
+    }
+
+    fn check_final_signature(&mut self) {
+        if self.done {
+            return;
+        }
+
+        let final_signature = match self.final_signature() {
+            Some(final_signature) => final_signature,
+            None => return,
+        };
+
+        if !self.result_sent {
+            debug!("Last level combined: {:#?}", final_signature.multisig);
+            info!("Last level finished receiving");
+
+            self.emit(AgentEvent::ThresholdReached { signature: final_signature.clone() });
+            self.finish(Ok(final_signature.clone()));
+
+            if self.config.extra_time.is_some() && final_signature.weight < self.total_weight {
+                info!("Improving signature with weight {}/{}", final_signature.weight, self.total_weight);
+                self.outputs.push(Output::ExtraTime);
+                return;
+            }
+        }
+        else if final_signature.weight < self.total_weight {
+            // keep improving until the extra time is over
+            return;
+        }
+
+        self.complete(final_signature);
+    }
+
+    /// Outputs the maximal signature. Then we're done, so we stop, or linger to help other peers.
+    fn complete(&mut self, final_signature: FinalSignature) {
+        info!("Finished with weight {}/{}", final_signature.weight, self.total_weight);
+        self.done = true;
+
+        self.emit(AgentEvent::MaximalReached { signature: final_signature.clone() });
+        self.finish_maximal(Ok(final_signature));
+
+        // observers that don't relay can't help anyone
+        if self.config.linger.is_some() && (!self.config.observer || self.config.relay) {
+            info!("Lingering to help other peers");
+            self.outputs.push(Output::Linger);
+            self.check_peers_done();
+        }
+        else {
+            self.stop();
+        }
+    }
+
+    /// Sends our best aggregate for `level` to a peer that is behind. This is done while
+    /// lingering and to answer catch-up requests.
+    fn help_peer(&mut self, peer_id: usize, level: usize) {
+        if level == 0 {
+            return;
+        }
+
+        let multisigs = self.combined(level - 1);
+        if !multisigs.is_empty() {
+            debug!("Helping peer {} at level {}", peer_id, level);
+            let individual = self.individual.clone();
+            self.send_to(vec![peer_id], multisigs, individual, level);
+        }
+    }
+
+    /// Answers the catch-up request of an observer at `address`, that takes the position of a
+    /// peer at `level`. At level 0 the observer takes our position, so it gets our individual
+    /// signature.
+    fn help_observer(&mut self, address: SocketAddr, level: usize) {
+        let multisigs = if level == 0 {
+            match &self.individual {
+                Some(individual) => {
+                    let multisig = MultiSignature::from_slots(individual, self.config.node_identity.slots());
+                    vec![(self.config.message_hash.clone(), multisig)]
+                },
+                None => Vec::new(),
+            }
+        }
+        else {
+            self.combined(level - 1)
+        };
+
+        if !multisigs.is_empty() {
+            debug!("Helping observer {} at level {}", address, level);
+        }
+
+        for (value, multisig) in multisigs {
+            let message = self.message(value, multisig, self.individual.clone(), level);
+            self.outputs.push(Output::SendTo { address, packet: Packet::Contribution(message) });
+        }
+    }
+
+    /// While lingering: Stops once all peers signaled that they're done
+    fn check_peers_done(&mut self) {
+        if self.done && self.peers_done.len() >= self.num_peers {
+            info!("All peers are done");
+            self.stop();
+        }
+    }
+
+    /// Fast path: When our aggregate for a level becomes complete, we additionally send it to
+    /// peers at that level that we didn't contact yet, but only once.
+    fn send_fast_path(&mut self, multisigs: Vec<(Blake2bHash, MultiSignature)>, level: usize) {
+        let peer_ids = {
+            let level = &mut self.levels[level];
+            if level.state.fast_path_sent {
+                return;
+            }
+            level.state.fast_path_sent = true;
+            let peer_ids = level.select_new_peers(self.config.fast_path_count, &self.blacklist);
+            level.state.send_peers_count += peer_ids.len();
+            peer_ids
+        };
+
+        debug!("Fast path for level {} to {:?}", level, peer_ids);
+        let individual = if self.levels[level].state.receive_completed { None } else { self.individual.clone() };
+        self.send_to(peer_ids, multisigs, individual, level);
+    }
+
+    fn send_update(&mut self, multisigs: Vec<(Blake2bHash, MultiSignature)>, level: usize, count: usize) {
+        let peer_ids = self.levels[level].select_next_peers(count, &self.blacklist);
+
+        let individual = if self.levels[level].state.receive_completed { None } else { self.individual.clone() };
+
+        self.send_to(peer_ids, multisigs, individual, level);
+    }
+
+    /// Sends the best pending contribution of `level` that is admitted by the level's
+    /// verification window to the verifier, unless a contribution of that level is being verified
+    /// already.
+    fn verify_next(&mut self, level: usize) {
+        if self.done || self.stopped {
+            return;
+        }
+
+        if self.verifying.values().any(|todo| todo.level() == level) {
+            return;
+        }
+
+        let todo = {
+            let level = &self.levels[level];
+            let pending = &mut self.pending[level.id];
+            let window = &level.state.window;
+
+            // drop the contributions that can't improve the store anymore
+            pending.rescore(&self.store);
+
+            let start = level.window_start(&self.blacklist);
+            let admitted = pending.pop_best_by(&self.store, |rank, _| window.admits(start, rank));
+
+            match admitted {
+                Some((todo, _score)) => todo,
+                None => return,
+            }
+        };
+
+        self.request_verification(todo);
+    }
+
+    fn request_verification(&mut self, todo: Todo) {
+        let verification = match &todo {
+            Todo::Individual { value, signature, origin, .. } => Verification::Individual { value: value.clone(), signature: signature.clone(), signer: *origin },
+            Todo::Multi { value, signature, .. } => Verification::Multisig { value: value.clone(), signature: signature.clone() },
+        };
+
+        let id = self.next_verification;
+        self.next_verification += 1;
+
+        self.verifying.insert(id, todo);
+        self.outputs.push(Output::Verify { id, verification });
+    }
+
+    /// Puts a verified contribution into the store and verifies the next one of its level
+    fn on_verified(&mut self, id: VerificationId, result: VerifyResult) {
+        let todo = match self.verifying.remove(&id) {
+            Some(todo) => todo,
+            None => {
+                warn!("Result for unknown verification {}", id);
+                return;
+            },
+        };
+        let origin = todo.origin();
+        let level = todo.level();
+
+        match result {
+            VerifyResult::Ok { votes } => {
+                self.emit(AgentEvent::ContributionVerified { peer: origin, level });
+
+                // the verification window moves on past the peer that sent the contribution
+                let sender = match &todo {
+                    Todo::Individual { message: Some(message), .. } => message.origin as usize,
+                    _ => origin,
+                };
+                if let Some(level) = self.levels.get_mut(level) {
+                    level.state.verified.insert(sender);
+                }
+
+                let todo = match todo {
+                    Todo::Multi { value, signature, level, origin, authenticated, .. } => Todo::Multi { value, signature, level, votes, origin, authenticated },
+                    todo => todo,
+                };
+                self.update_window(level, true);
+                self.put_verified(todo);
+            },
+            VerifyResult::InvalidSignature => {
+                warn!("Invalid signature from {}: {:?}", origin, todo);
+                self.emit(AgentEvent::ContributionRejected { peer: origin, level, reason: result });
+                match todo {
+                    Todo::Individual { message: Some(message), authenticated, .. } => {
+                        if message.origin as usize == origin {
+                            self.accuse(Message::clone(&message), Misbehaviour::InvalidIndividual, authenticated);
+                        }
+                        else if authenticated {
+                            // the origin of the message relayed an invalid partial signature
+                            self.report_invalid(message.origin as usize);
+                        }
+                    },
+                    Todo::Multi { authenticated: true, .. } => self.report_invalid(origin),
+                    _ => debug!("Invalid contribution can't be attributed to {}", origin),
+                }
+                self.update_window(level, false);
+            },
+            _ => {
+                warn!("Rejected signature: {:?}", result);
+                warn!("{:#?}", todo);
+                self.emit(AgentEvent::ContributionRejected { peer: origin, level, reason: result });
+            },
+        }
+
+        self.verify_next(level);
+    }
+
+    fn update_window(&mut self, level: usize, success: bool) {
+        if let Some(level) = self.levels.get_mut(level) {
+            if success {
+                level.state.window.on_success();
+            }
+            else {
+                level.state.window.on_failure();
+            }
+        }
+    }
+
+    /// Puts a verified contribution into the store, if it still improves it
+    fn put_verified(&mut self, todo: Todo) {
+        // the store might have changed while the contribution was verified
+        if todo.evaluate(&self.store) == 0 {
+            return;
+        }
+
+        let level = todo.level();
+        let value = todo.value().clone();
+        let weight_before = self.store.best(&value, level).map(|best| self.store.weight(best));
+        todo.put(&mut self.store);
+        let weight_after = self.store.best(&value, level).map(|best| self.store.weight(best));
+
+        if weight_after > weight_before {
+            if let Some(weight) = weight_after {
+                self.emit(AgentEvent::SignatureImproved { level, weight });
+            }
+        }
+
+        self.check_completed_level(level);
+        self.check_improved();
+        self.check_final_signature();
+    }
+
+    fn on_message(&mut self, message: Message, from: SocketAddr) {
+        if message.session != self.config.session {
+            debug!("Ignoring message for session {}", message.session);
+            return;
+        }
+
+        let origin = message.origin as usize;
+        let level = message.level as usize;
+        let authenticated = self.is_authentic(origin, &from);
+
+        if message.value != self.config.message_hash && !self.config.multi_value {
+            debug!("Ignoring message for other value from {}", origin);
+            return;
+        }
+
+        if self.blacklist.contains(origin) {
+            debug!("Ignoring message from blacklisted peer {}", origin);
+            return;
+        }
+
+        if let Some(level) = self.levels.get(level) {
+            if level.rank(origin).is_none() {
+                warn!("Peer {} is not at level {}", origin, level.id);
+                return;
+            }
+        }
+        else {
+            error!("Invalid level in message: {}", level);
+            return;
+        }
+
+        if !self.within_partition(&message.multisig, level) {
+            self.accuse(message, Misbehaviour::SignersOutsidePartition, authenticated);
+            return;
+        }
+
+        // only trust the done flag if the message came from the origin's address
+        let done = message.done && authenticated;
+        if done {
+            self.peers_done.insert(origin);
+        }
+
+        if self.done {
+            // we're lingering, so help the peer if it's still behind
+            if !done {
+                self.help_peer(origin, level);
+            }
+            self.check_peers_done();
+            return;
+        }
+
+        // with a threshold key, we might still need the partial signatures of a complete level
+        if self.levels[level].state.receive_completed && self.config.threshold_key.is_none() {
+            return;
+        }
+
+        // Queue the contribution unverified. It's scored against the store and only verified
+        // once it's the best contribution of its level that the verification window admits.
+        let rank = self.levels[level].rank(origin).unwrap();
+        let raw = if message.individual.is_some() || !message.partials.is_empty() { Some(Arc::new(message.clone())) } else { None };
+        let Message { value, multisig, individual, partials, .. } = message;
+        let votes = self.store.weight(&multisig);
+        self.pending[level].push(Todo::Multi { value: value.clone(), signature: multisig, level, votes, origin, authenticated }, rank, &self.store);
+        if let Some(signature) = individual {
+            self.pending[level].push(Todo::Individual { value: value.clone(), signature, level, origin, message: raw.clone(), authenticated }, rank, &self.store);
+        }
+
+        // the partial signatures are verified like individual signatures of their signers
+        if self.config.threshold_key.is_some() {
+            for PartialSignature { signer, signature } in partials {
+                let signer = signer as usize;
+                if self.levels[level].rank(signer).is_none() {
+                    debug!("Ignoring partial signature of {} from {}, which is not at level {}", signer, origin, level);
+                    continue;
+                }
+                self.pending[level].push(Todo::Individual { value: value.clone(), signature, level, origin: signer, message: raw.clone(), authenticated }, rank, &self.store);
+            }
+        }
+
+        self.verify_next(level);
+    }
+
+    fn on_request(&mut self, request: CatchUpRequest, from: SocketAddr) {
+        if request.session != self.config.session {
+            return;
+        }
+
+        let origin = request.origin as usize;
+
+        if self.blacklist.contains(origin) {
+            debug!("Ignoring catch-up request from blacklisted peer {}", origin);
+            return;
+        }
+
+        // the requester is at the same level for us, as we are for it
+        let level = self.levels.iter()
+            .find(|level| level.rank(origin).is_some())
+            .map(|level| level.id);
+
+        if let Some(level) = level {
+            if level >= request.min_level as usize && level <= request.max_level as usize {
+                debug!("Answering catch-up request from {} at level {}", origin, level);
+                if request.observer {
+                    self.help_observer(from, level);
+                }
+                else {
+                    self.help_peer(origin, level);
+                }
+            }
+        }
+        else {
+            warn!("Catch-up request from unknown peer {}", origin);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::collections::VecDeque;
+
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
+
+    use hash::{Hash, Blake2bHash};
+    use bls::bls12_381::KeyPair;
+
+    use crate::handel::{
+        Config, VerifyResult, Packet, Message, MultiSignature, FinalSignature, ThresholdKey, ThresholdSetup,
+    };
+    use crate::handel::testing::{address, create_key_pairs, create_identities};
+    use super::{Protocol, Input, Output, Verification};
+
+    /// Creates the protocols for `num_nodes` nodes. The last `num_dissenting` nodes vote for
+    /// another value.
+    fn create_protocols(num_nodes: usize, threshold: usize, num_dissenting: usize) -> Vec<Protocol> {
+        create_protocols_with_keys(create_key_pairs(num_nodes), threshold, num_dissenting, None)
+    }
+
+    /// Creates the protocols for the nodes with `key_pairs`, which might be shares of
+    /// `threshold_key`
+    fn create_protocols_with_keys(key_pairs: Vec<KeyPair>, threshold: usize, num_dissenting: usize, threshold_key: Option<ThresholdKey>) -> Vec<Protocol> {
+        let num_nodes = key_pairs.len();
+
+        let registry = Arc::new(create_identities(&key_pairs));
+
+        key_pairs.into_iter()
+            .enumerate()
+            .map(|(id, key_pair)| {
+                let message = if id + num_dissenting < num_nodes { "foobar" } else { "barfoo" };
+                let mut config = Config::for_test(&registry, id, key_pair, threshold);
+                config.message_hash = message.hash::<Blake2bHash>();
+                config.multi_value = num_dissenting > 0;
+                config.threshold_key = threshold_key.clone();
+                // keep helping peers that are behind
+                config.linger = Some(Duration::from_secs(1));
+                Protocol::new(config, Arc::clone(&registry))
+            })
+            .collect()
+    }
+
+    /// The contribution of `id` at `level`, that only contains its own signature
+    fn contribution(protocols: &[Protocol], id: usize, level: usize, done: bool) -> Message {
+        let individual = protocols[id].individual.clone().unwrap();
+        Message {
+            session: 0,
+            value: protocols[id].config().message_hash.clone(),
+            origin: id as u16,
+            level: level as u8,
+            multisig: MultiSignature::from_individual(&individual, id),
+            individual: Some(individual),
+            partials: Vec::new(),
+            done,
+            origin_signature: None,
+        }
+    }
+
+    /// Passes `input` to the protocol and rejects all verifications that it requests as invalid
+    fn reject_all(protocol: &mut Protocol, input: Input) {
+        let mut inputs = VecDeque::new();
+        inputs.push_back(input);
+
+        while let Some(input) = inputs.pop_front() {
+            for output in protocol.handle(input) {
+                if let Output::Verify { id, .. } = output {
+                    inputs.push_back(Input::Verified { id, result: VerifyResult::InvalidSignature });
+                }
+            }
+        }
+    }
+
+    /// The IDs of the verifications that were requested
+    fn verifications(outputs: Vec<Output>) -> Vec<usize> {
+        outputs.into_iter()
+            .filter_map(|output| match output {
+                Output::Verify { id, .. } => Some(id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Routes the outputs of the protocols in memory and drives their timers, until all of them
+    /// produced a result. Returns the result of every node. Nodes after the committee must be
+    /// observers, that are reachable at the address of their index.
+    fn run(protocols: &mut [Protocol]) -> Vec<Option<Option<FinalSignature>>> {
+        let num_nodes = protocols.len();
+        let num_levels = protocols[0].num_levels();
+
+        let mut inputs = (0 .. num_nodes)
+            .map(|id| (id, Input::Start))
+            .collect::<VecDeque<(usize, Input)>>();
+        let mut results = vec![None; num_nodes];
+
+        for round in 0 .. 32 {
+            while let Some((id, input)) = inputs.pop_front() {
+                for output in protocols[id].handle(input) {
+                    match output {
+                        Output::Send { to, packet: Packet::Contribution(message) } => {
+                            inputs.push_back((to, Input::Received { message, from: address(id) }))
+                        },
+                        Output::Send { to, packet: Packet::CatchUp(request) } => {
+                            inputs.push_back((to, Input::CatchUpRequested { request, from: address(id) }))
+                        },
+                        Output::SendTo { address, packet: Packet::Contribution(message) } => {
+                            inputs.push_back((address.port() as usize - 12000, Input::Received { message, from: address(id) }))
+                        },
+                        Output::Verify { id: verification_id, verification } => {
+                            // every signature is valid and every signer has weight 1
+                            let votes = match verification {
+                                Verification::Individual { .. } => 1,
+                                Verification::Multisig { signature, .. } => signature.len(),
+                            };
+                            inputs.push_back((id, Input::Verified { id: verification_id, result: VerifyResult::Ok { votes } }));
+                        },
+                        Output::Result(result) => {
+                            assert!(results[id].is_none(), "Node {} output more than one result", id);
+                            results[id] = Some(result.ok());
+                        },
+                        _ => {},
+                    }
+                }
+            }
+
+            if results.iter().all(|result| result.is_some()) {
+                break;
+            }
+
+            // timers
+            for id in 0 .. num_nodes {
+                if round < num_levels {
+                    inputs.push_back((id, Input::Timeout { level: round }));
+                }
+                inputs.push_back((id, Input::Update));
+            }
+        }
+
+        results
+    }
+
+    #[test]
+    fn test_aggregation_without_io() {
+        let num_nodes = 8;
+        let mut protocols = create_protocols(num_nodes, num_nodes, 0);
+
+        for (id, result) in run(&mut protocols).into_iter().enumerate() {
+            let weight = result.and_then(|result| result.map(|signature| signature.weight));
+            assert_eq!(weight, Some(num_nodes), "Node {} didn't produce the full signature", id);
+        }
+    }
+
+    #[test]
+    fn test_multi_value_aggregation() {
+        // 6 nodes vote for "foobar", 2 for "barfoo"
+        let num_nodes = 8;
+        let threshold = 5;
+        let mut protocols = create_protocols(num_nodes, threshold, 2);
+
+        for (id, result) in run(&mut protocols).into_iter().enumerate() {
+            let signature = result
+                .and_then(|result| result)
+                .unwrap_or_else(|| panic!("Node {} didn't produce a signature", id));
+            assert_eq!(signature.value, "foobar".hash::<Blake2bHash>(), "Node {} reports the wrong value", id);
+            assert!(signature.weight >= threshold);
+        }
+    }
+
+    #[test]
+    fn test_shuffling_is_deterministic() {
+        // the peers are shuffled with the same seed
+        let first = create_protocols(8, 8, 0);
+        let second = create_protocols(8, 8, 0);
+
+        for (a, b) in first.iter().zip(&second) {
+            for (level_a, level_b) in a.levels.iter().zip(&b.levels) {
+                assert_eq!(level_a.peer_ids, level_b.peer_ids);
+            }
+        }
+    }
+
+    #[test]
+    fn test_abstaining_member() {
+        let num_nodes = 8;
+        let mut protocols = create_protocols(num_nodes, num_nodes - 1, 0);
+
+        // node 5 doesn't sign, but still aggregates and relays
+        let mut config = protocols[5].config().clone();
+        config.key_pair = None;
+        protocols[5] = Protocol::new(config, Arc::clone(&protocols[5].identities));
+
+        for (id, result) in run(&mut protocols).into_iter().enumerate() {
+            let signature = result
+                .and_then(|result| result)
+                .unwrap_or_else(|| panic!("Node {} didn't produce a signature", id));
+            assert!(!signature.multisig.signers.contains(5), "Node {} has a signature of the abstaining node", id);
+            assert_eq!(signature.weight, num_nodes - 1);
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let num_nodes = 8;
+        let mut protocols = create_protocols(num_nodes, num_nodes, 0);
+
+        // the observer takes the position of node 3
+        let mut config = protocols[3].config().clone();
+        config.observer = true;
+        config.key_pair = None;
+        config.linger = None;
+        let observer = Protocol::new(config, Arc::clone(&protocols[3].identities));
+        protocols.push(observer);
+
+        let results = run(&mut protocols);
+        let weight = results[num_nodes].clone()
+            .and_then(|result| result.map(|signature| signature.weight));
+        assert_eq!(weight, Some(num_nodes), "Observer didn't produce the full signature");
+    }
+
+    #[test]
+    fn test_threshold_aggregation() {
+        let num_nodes = 8;
+        let mut csprng = ChaChaRng::from_seed([42; 32]);
+        let setup = ThresholdSetup::deal(5, num_nodes, &mut csprng).unwrap();
+        let mut protocols = create_protocols_with_keys(setup.shares.clone(), 5, 0, Some(setup.key.clone()));
+
+        for (id, result) in run(&mut protocols).into_iter().enumerate() {
+            let group_signature = result
+                .and_then(|result| result)
+                .and_then(|signature| signature.group_signature)
+                .unwrap_or_else(|| panic!("Node {} didn't produce a group signature", id));
+            assert!(setup.key.verify("foobar".hash::<Blake2bHash>(), &group_signature), "Group signature of node {} is invalid", id);
+        }
+    }
+
+    #[test]
+    fn test_spoofed_origin_is_not_blacklisted() {
+        let mut protocols = create_protocols(4, 4, 0);
+        protocols[0].handle(Input::Start);
+
+        // contribution of node 1 at level 1
+        let message = contribution(&protocols, 1, 1, false);
+
+        // the invalid contribution was sent from another address, so it's not held against node 1
+        reject_all(&mut protocols[0], Input::Received { message: message.clone(), from: address(3) });
+        assert!(protocols[0].blacklist().is_empty(), "Node 1 was blacklisted for a spoofed message");
+
+        reject_all(&mut protocols[0], Input::Received { message, from: address(1) });
+        assert!(protocols[0].blacklist().contains(1), "Node 1 was not blacklisted");
+    }
+
+    #[test]
+    fn test_spoofed_done_is_ignored() {
+        let mut protocols = create_protocols(4, 4, 0);
+        protocols[0].handle(Input::Start);
+
+        let message = contribution(&protocols, 1, 1, true);
+
+        protocols[0].handle(Input::Received { message: message.clone(), from: address(3) });
+        assert!(!protocols[0].peers_done.contains(1), "Spoofed message marked node 1 as done");
+
+        protocols[0].handle(Input::Received { message, from: address(1) });
+        assert!(protocols[0].peers_done.contains(1), "Node 1 was not marked as done");
+    }
+
+    #[test]
+    fn test_evidence_must_be_signed() {
+        let mut protocols = create_protocols(4, 4, 0);
+        protocols[0].handle(Input::Start);
+
+        // at level 1 node 1 can only send its own signature, but claims that node 2 signed
+        let individual = protocols[1].individual.clone().unwrap();
+        let multisig = MultiSignature::from_individual(&individual, 2);
+        let message = protocols[1].message("foobar".hash::<Blake2bHash>(), multisig, None, 1);
+
+        // without the signature of node 1, anyone could have sent the message. The messages are
+        // relayed by node 3, so that node 1 isn't blacklisted.
+        let mut unsigned = message.clone();
+        unsigned.origin_signature = None;
+        protocols[0].handle(Input::Received { message: unsigned, from: address(3) });
+        assert!(protocols[0].evidence().is_empty(), "Unsigned message was recorded as evidence");
+
+        protocols[0].handle(Input::Received { message, from: address(3) });
+        assert_eq!(protocols[0].evidence().len(), 1);
+    }
+
+    #[test]
+    fn test_window_starts_at_unverified_rank() {
+        let mut protocols = create_protocols(4, 4, 0);
+
+        // only verify one rank at a time
+        let mut config = protocols[0].config().clone();
+        config.window_initial = 1;
+        config.window_max = 1;
+        protocols[0] = Protocol::new(config, Arc::clone(&protocols[0].identities));
+        protocols[0].handle(Input::Start);
+
+        // the peers at level 2 in the order of their ranks, as shuffled with the seed
+        let (first, second) = (protocols[0].levels[2].peer_ids[0], protocols[0].levels[2].peer_ids[1]);
+
+        // the contributions only contain the multi-signatures
+        let multisig_only = |protocols: &[Protocol], id: usize| {
+            let mut message = contribution(protocols, id, 2, false);
+            message.individual = None;
+            Input::Received { message, from: address(id) }
+        };
+
+        // the second peer is outside of the window, even though it's the only pending contribution
+        let input = multisig_only(&protocols, second);
+        assert!(verifications(protocols[0].handle(input)).is_empty(), "Contribution outside of the window was verified");
+
+        // the first peer is verified, and then the window moves on to the second one
+        let input = multisig_only(&protocols, first);
+        let ids = verifications(protocols[0].handle(input));
+        assert_eq!(ids.len(), 1);
+        let outputs = protocols[0].handle(Input::Verified { id: ids[0], result: VerifyResult::Ok { votes: 1 } });
+        assert_eq!(verifications(outputs).len(), 1, "Contribution of the second peer was not verified");
+    }
+
+    #[test]
+    fn test_verifies_one_contribution_per_level() {
+        let mut protocols = create_protocols(4, 4, 0);
+        protocols[0].handle(Input::Start);
+
+        // both contributions at level 2 are in the window, but only one is verified at a time
+        let mut ids = Vec::new();
+        for id in 2 .. 4 {
+            let mut message = contribution(&protocols, id, 2, false);
+            message.individual = None;
+            ids.extend(verifications(protocols[0].handle(Input::Received { message, from: address(id) })));
+        }
+        assert_eq!(ids.len(), 1);
+
+        // the other one is verified once the first verification finished
+        let outputs = protocols[0].handle(Input::Verified { id: ids[0], result: VerifyResult::Ok { votes: 1 } });
+        assert_eq!(verifications(outputs).len(), 1);
+    }
+}
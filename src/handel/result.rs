@@ -0,0 +1,101 @@
+use std::fmt;
+use std::time::Duration;
+
+use failure::Fail;
+
+use bls::bls12_381::Signature;
+use hash::Blake2bHash;
+
+use crate::handel::MultiSignature;
+
+
+/// A combined signature and the total weight of its signers
+#[derive(Clone, Debug)]
+pub struct FinalSignature {
+    /// Hash of the value that was signed
+    pub value: Blake2bHash,
+
+    pub multisig: MultiSignature,
+    pub weight: usize,
+
+    /// The signature of the group, if a threshold key is used. It verifies against the group's
+    /// public key.
+    pub group_signature: Option<Signature>,
+}
+
+
+/// A combined signature of all levels that is better than all previous ones
+#[derive(Clone, Debug)]
+pub struct ImprovedSignature {
+    /// Hash of the value that was signed
+    pub value: Blake2bHash,
+
+    pub multisig: MultiSignature,
+
+    /// Total weight of the signers
+    pub weight: usize,
+
+    /// Time since the agent started
+    pub elapsed: Duration,
+}
+
+
+/// How far a level got
+#[derive(Clone, Debug)]
+pub struct LevelCompletion {
+    pub level: usize,
+
+    /// Weight of the best signatures we received for this level, for all values
+    pub weight: usize,
+
+    /// Total weight of all identities at this level
+    pub total_weight: usize,
+
+    /// Whether we received the complete signature for this level
+    pub complete: bool,
+}
+
+
+/// The best result that an agent reached, if it didn't reach the threshold
+#[derive(Clone, Debug)]
+pub struct PartialResult {
+    /// Hash of the value that `best` signs
+    pub value: Option<Blake2bHash>,
+
+    /// The best combined signature, i.e. the one for the highest level that we could combine. If
+    /// multiple values were signed, this is the one with the highest weight.
+    pub best: Option<MultiSignature>,
+
+    /// Total weight of the signers of `best`
+    pub weight: usize,
+
+    /// Completion of every level
+    pub levels: Vec<LevelCompletion>,
+}
+
+impl fmt::Display for PartialResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "weight {}", self.weight)
+    }
+}
+
+
+#[derive(Clone, Debug, Fail)]
+pub enum AggregationError {
+    #[fail(display = "Deadline exceeded with {}", _0)]
+    DeadlineExceeded(PartialResult),
+    #[fail(display = "Agent stopped with {}", _0)]
+    Stopped(PartialResult),
+}
+
+impl AggregationError {
+    pub fn partial_result(&self) -> &PartialResult {
+        match self {
+            AggregationError::DeadlineExceeded(partial) => partial,
+            AggregationError::Stopped(partial) => partial,
+        }
+    }
+}
+
+
+pub type HandelResult = Result<FinalSignature, AggregationError>;
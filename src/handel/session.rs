@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::io::Error as IoError;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream, future};
+use futures::sync::mpsc::UnboundedSender;
+use futures_cpupool::CpuPool;
+use parking_lot::RwLock;
+use tokio::timer::Interval;
+
+use hash::Blake2bHash;
+
+use crate::handel::{
+    Config, IdentityRegistry, HandelAgent, AgentProcessor, Handler, Message, SessionId,
+    VerifierBackend, BoxVerifier, Boxed, ThreadPoolVerifier, BatchVerifier, CatchUpRequest, Packet,
+};
+
+
+/// Returns the message hash for a session, or `None` if the session should not be started
+pub type SessionResolver = Box<dyn Fn(SessionId) -> Option<Blake2bHash> + Send + Sync>;
+
+
+/// Maximum number of packets that are buffered for a session that wasn't started yet
+const MAX_BUFFERED_PACKETS: usize = 1024;
+
+
+struct Session {
+    agent: Arc<HandelAgent>,
+    started: Instant,
+}
+
+
+/// Buffers the packets of sessions that will be started later, so that they aren't dropped when
+/// they arrive before the agent of their session runs
+pub struct PacketBuffer {
+    /// session ID -> (time of registration, buffered packets)
+    expected: RwLock<HashMap<SessionId, (Instant, Vec<(Packet, SocketAddr)>)>>,
+}
+
+impl PacketBuffer {
+    pub fn new() -> Self {
+        Self {
+            expected: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Registers a session, whose packets are buffered from now on
+    pub fn expect(&self, session_id: SessionId) {
+        self.expected.write()
+            .entry(session_id)
+            .or_insert_with(|| (Instant::now(), Vec::new()));
+    }
+
+    /// Buffers a packet for its session, if the session is expected. `started` returns the agent
+    /// of the session, if it's running. It's called while the buffer is locked, so that no packet
+    /// is buffered after the session's packets were released. If the session was started, the
+    /// packet is passed to its agent instead.
+    pub fn buffer<F>(&self, session_id: SessionId, packet: Packet, sender_address: SocketAddr, started: F) -> Box<dyn Future<Item=(), Error=IoError> + Send>
+        where F: FnOnce() -> Option<Arc<HandelAgent>>
+    {
+        let mut expected = self.expected.write();
+
+        if let Some(agent) = started() {
+            drop(expected);
+            return deliver(&agent, packet, sender_address);
+        }
+
+        match expected.get_mut(&session_id) {
+            Some((_, buffered)) if buffered.len() < MAX_BUFFERED_PACKETS => buffered.push((packet, sender_address)),
+            Some(_) => debug!("Dropping packet for session {}, its buffer is full", session_id),
+            None => debug!("Dropping packet for unknown session {}", session_id),
+        }
+
+        Box::new(future::ok::<(), IoError>(()))
+    }
+
+    /// Passes the buffered packets of a session to its agent, which must be running already.
+    ///
+    /// This must be called from within a tokio runtime, since the packets are handled on it.
+    pub fn release(&self, session_id: SessionId, agent: &Arc<HandelAgent>) {
+        if let Some((_, buffered)) = self.expected.write().remove(&session_id) {
+            debug!("Passing {} buffered packets to session {}", buffered.len(), session_id);
+            for (packet, sender_address) in buffered {
+                tokio::spawn(deliver(agent, packet, sender_address)
+                    .map_err(|e| warn!("Failed to handle buffered packet: {}", e)));
+            }
+        }
+    }
+
+    /// Drops the packets of sessions that were expected for longer than `timeout`
+    pub fn expire(&self, timeout: Duration) {
+        let now = Instant::now();
+        self.expected.write()
+            .retain(|_, (registered, _)| now.duration_since(*registered) < timeout);
+    }
+}
+
+impl Default for PacketBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// Runs multiple Handel aggregations over one network and routes incoming messages to the
+/// agent of their session.
+pub struct SessionManager {
+    /// Configuration template for new sessions. `message_hash` and `session` are set per session.
+    config: Config,
+
+    /// All known identities, shared by all sessions
+    identities: Arc<IdentityRegistry>,
+
+    /// Sink to send messages to other peers
+    sink: UnboundedSender<(Packet, SocketAddr)>,
+
+    /// Thread pool shared by the verifiers of all sessions
+    workers: Option<CpuPool>,
+
+    /// Resolves the message hash for sessions that are started by incoming messages
+    resolver: Option<SessionResolver>,
+
+    /// Sessions that run longer than this are removed
+    session_timeout: Duration,
+
+    /// Running sessions
+    sessions: RwLock<HashMap<SessionId, Session>>,
+
+    /// Sessions that were removed, so that late messages don't start them again
+    /// session ID -> time of removal
+    closed: RwLock<HashMap<SessionId, Instant>>,
+
+    /// Sessions that will be started later. Their packets are buffered until then.
+    expected: PacketBuffer,
+}
+
+impl SessionManager {
+    pub fn new(config: Config, identities: IdentityRegistry, sink: UnboundedSender<(Packet, SocketAddr)>, session_timeout: Duration) -> Self {
+        let workers = match config.verifier {
+            VerifierBackend::ThreadPool { num_workers: Some(n) } |
+            VerifierBackend::Batch { num_workers: Some(n), .. } => Some(CpuPool::new(n)),
+            VerifierBackend::ThreadPool { num_workers: None } |
+            VerifierBackend::Batch { num_workers: None, .. } => Some(CpuPool::new_num_cpus()),
+            VerifierBackend::Dummy => None,
+        };
+
+        Self {
+            config,
+            identities: Arc::new(identities),
+            sink,
+            workers,
+            resolver: None,
+            session_timeout,
+            sessions: RwLock::new(HashMap::new()),
+            closed: RwLock::new(HashMap::new()),
+            expected: PacketBuffer::new(),
+        }
+    }
+
+    /// Sets the resolver that is used to start sessions on demand when a message for an unknown
+    /// session arrives. Without a resolver such messages are dropped.
+    pub fn with_resolver<F>(mut self, resolver: F) -> Self
+        where F: Fn(SessionId) -> Option<Blake2bHash> + Send + Sync + 'static
+    {
+        self.resolver = Some(Box::new(resolver));
+        self
+    }
+
+    /// Returns the agent of a running session
+    pub fn session(&self, session_id: SessionId) -> Option<Arc<HandelAgent>> {
+        self.sessions.read().get(&session_id)
+            .map(|session| Arc::clone(&session.agent))
+    }
+
+    pub fn num_sessions(&self) -> usize {
+        self.sessions.read().len()
+    }
+
+    /// Registers a session that will be started later. Packets for it are buffered until it's
+    /// started, instead of being dropped. They're dropped when the session isn't started within
+    /// the session timeout.
+    pub fn expect_session(&self, session_id: SessionId) {
+        if self.session(session_id).is_none() {
+            self.expected.expect(session_id);
+        }
+    }
+
+    /// Starts a session for `message_hash`, or returns the agent if the session is already running.
+    ///
+    /// This must be called from within a tokio runtime, since the agent is spawned on it.
+    pub fn start_session(&self, session_id: SessionId, message_hash: Blake2bHash) -> Arc<HandelAgent> {
+        let mut sessions = self.sessions.write();
+
+        if let Some(session) = sessions.get(&session_id) {
+            return Arc::clone(&session.agent);
+        }
+
+        info!("Starting session {}", session_id);
+
+        let mut config = self.config.clone();
+        config.session = session_id;
+        config.message_hash = message_hash;
+
+        let verifier = self.create_verifier(&config);
+        let agent = Arc::new(HandelAgent::with_verifier(config, Arc::clone(&self.identities), self.sink.clone(), verifier));
+        let (agent_fut, _handle) = agent.spawn();
+        tokio::spawn(agent_fut);
+
+        sessions.insert(session_id, Session {
+            agent: Arc::clone(&agent),
+            started: Instant::now(),
+        });
+        self.closed.write().remove(&session_id);
+
+        // NOTE: Packets are only buffered while the session isn't running, so once it was
+        // inserted, no packets are added to the buffer anymore.
+        drop(sessions);
+        self.expected.release(session_id, &agent);
+
+        agent
+    }
+
+    /// Removes sessions that stopped or ran longer than the session timeout. Expired sessions are
+    /// stopped.
+    pub fn collect_garbage(&self) {
+        let now = Instant::now();
+        let session_timeout = self.session_timeout;
+
+        let mut closed = self.closed.write();
+        closed.retain(|_, removed| now.duration_since(*removed) < session_timeout);
+
+        self.expected.expire(session_timeout);
+
+        self.sessions.write().retain(|session_id, session| {
+            let expired = now.duration_since(session.started) >= session_timeout;
+            if session.agent.is_stopped() || expired {
+                debug!("Removing session {}: stopped={}, expired={}", session_id, session.agent.is_stopped(), expired);
+                session.agent.stop();
+                closed.insert(*session_id, now);
+                false
+            }
+            else {
+                true
+            }
+        });
+    }
+
+    /// Creates a future that periodically collects garbage
+    pub fn garbage_collector(this: &Arc<Self>, period: Duration) -> Box<dyn Future<Item=(), Error=()> + Send> {
+        let this = Arc::clone(this);
+        Box::new(Interval::new_interval(period)
+            .map_err(|e| {
+                error!("Interval error: {}", e);
+            })
+            .for_each(move |_instant| {
+                this.collect_garbage();
+                future::ok::<(), ()>(())
+            }))
+    }
+
+    fn create_verifier(&self, config: &Config) -> BoxVerifier {
+        match (&self.workers, &config.verifier) {
+            (Some(workers), VerifierBackend::ThreadPool { .. }) => {
+                Boxed::boxed(ThreadPoolVerifier::with_pool(config.threshold, Arc::clone(&self.identities), workers.clone()))
+            },
+            (Some(workers), VerifierBackend::Batch { batch_size, batch_delay, .. }) => {
+                Boxed::boxed(BatchVerifier::with_pool(config.threshold, Arc::clone(&self.identities), workers.clone(), *batch_size, *batch_delay))
+            },
+            _ => config.create_verifier(Arc::clone(&self.identities)),
+        }
+    }
+
+    fn get_or_start(&self, session_id: SessionId) -> Option<Arc<HandelAgent>> {
+        if let Some(agent) = self.session(session_id) {
+            return Some(agent);
+        }
+
+        if self.closed.read().contains_key(&session_id) {
+            return None;
+        }
+
+        let message_hash = (self.resolver.as_ref()?)(session_id)?;
+        Some(self.start_session(session_id, message_hash))
+    }
+
+    /// Passes a packet to the agent of its session. If the session is expected, but not running
+    /// yet, the packet is buffered.
+    fn dispatch(&self, session_id: SessionId, packet: Packet, sender_address: SocketAddr) -> Box<dyn Future<Item=(), Error=IoError> + Send> {
+        if let Some(agent) = self.get_or_start(session_id) {
+            return deliver(&agent, packet, sender_address);
+        }
+
+        // the session might have been started meanwhile
+        self.expected.buffer(session_id, packet, sender_address, || self.session(session_id))
+    }
+}
+
+
+/// Passes a packet to an agent
+fn deliver(agent: &Arc<HandelAgent>, packet: Packet, sender_address: SocketAddr) -> Box<dyn Future<Item=(), Error=IoError> + Send> {
+    match packet {
+        Packet::Contribution(message) => agent.on_message(message, sender_address),
+        Packet::CatchUp(request) => agent.on_request(request, sender_address),
+    }
+}
+
+
+impl Handler for Arc<SessionManager> {
+    fn on_message(&self, message: Message, sender_address: SocketAddr) -> Box<dyn Future<Item=(), Error=IoError> + Send> {
+        self.dispatch(message.session, Packet::Contribution(message), sender_address)
+    }
+
+    fn on_request(&self, request: CatchUpRequest, sender_address: SocketAddr) -> Box<dyn Future<Item=(), Error=IoError> + Send> {
+        self.dispatch(request.session, Packet::CatchUp(request), sender_address)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::{Future, future};
+    use futures::sync::mpsc::unbounded;
+    use tokio::runtime::current_thread::Runtime;
+
+    use hash::{Hash, Blake2bHash};
+
+    use crate::handel::{Config, CatchUpRequest, Packet};
+    use crate::handel::testing::{address, create_key_pairs, create_identities};
+    use super::SessionManager;
+
+    fn create_manager(session_timeout: Duration) -> SessionManager {
+        let key_pairs = create_key_pairs(1);
+        let registry = create_identities(&key_pairs);
+        let config = Config::for_test(&registry, 0, key_pairs[0].clone(), 1);
+
+        // the receiver is dropped, so everything that is sent is lost
+        let (sink, _) = unbounded();
+        SessionManager::new(config, registry, sink, session_timeout)
+    }
+
+    #[test]
+    fn test_resolve_sessions() {
+        let mut runtime = Runtime::new().unwrap();
+
+        runtime.block_on(future::lazy(|| {
+            // only even sessions are resolved
+            let manager = create_manager(Duration::from_secs(60))
+                .with_resolver(|session| if session % 2 == 0 { Some(format!("session {}", session).as_str().hash::<Blake2bHash>()) } else { None });
+
+            let agent = manager.get_or_start(2).expect("Session 2 was not started");
+            assert_eq!(agent.session(), 2);
+            assert!(manager.get_or_start(3).is_none());
+            assert_eq!(manager.num_sessions(), 1);
+
+            // a running session is not started again
+            assert!(Arc::ptr_eq(&agent, &manager.get_or_start(2).unwrap()));
+            assert_eq!(manager.num_sessions(), 1);
+
+            future::ok::<(), ()>(())
+        })).unwrap();
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let mut runtime = Runtime::new().unwrap();
+
+        runtime.block_on(future::lazy(|| {
+            let manager = create_manager(Duration::from_secs(60))
+                .with_resolver(|session| Some(format!("session {}", session).as_str().hash::<Blake2bHash>()));
+
+            let stopped = manager.start_session(1, "foobar".hash::<Blake2bHash>());
+            manager.start_session(2, "barfoo".hash::<Blake2bHash>());
+            stopped.stop();
+
+            // only the stopped session is removed
+            manager.collect_garbage();
+            assert_eq!(manager.num_sessions(), 1);
+            assert!(manager.session(1).is_none());
+            assert!(manager.session(2).is_some());
+
+            // late messages don't start a removed session again
+            assert!(manager.get_or_start(1).is_none());
+
+            future::ok::<(), ()>(())
+        })).unwrap();
+    }
+
+    #[test]
+    fn test_session_timeout() {
+        let mut runtime = Runtime::new().unwrap();
+
+        runtime.block_on(future::lazy(|| {
+            let manager = create_manager(Duration::from_secs(0));
+
+            let agent = manager.start_session(1, "foobar".hash::<Blake2bHash>());
+            manager.collect_garbage();
+
+            // the expired session was stopped and removed
+            assert_eq!(manager.num_sessions(), 0);
+            assert!(agent.is_stopped());
+
+            future::ok::<(), ()>(())
+        })).unwrap();
+    }
+
+    #[test]
+    fn test_buffer_expected_session() {
+        let mut runtime = Runtime::new().unwrap();
+
+        runtime.block_on(future::lazy(|| {
+            let manager = create_manager(Duration::from_secs(60));
+            manager.expect_session(1);
+
+            let request = |session| Packet::CatchUp(CatchUpRequest { session, origin: 0, min_level: 0, max_level: 0, observer: false });
+
+            // only the packet for the expected session is buffered
+            manager.dispatch(1, request(1), address(1)).wait().unwrap();
+            manager.dispatch(2, request(2), address(1)).wait().unwrap();
+            assert_eq!(manager.expected.expected.read().get(&1).map(|(_, buffered)| buffered.len()), Some(1));
+            assert!(manager.expected.expected.read().get(&2).is_none());
+            assert_eq!(manager.num_sessions(), 0);
+
+            // the buffered packets are passed to the session when it's started
+            manager.start_session(1, "foobar".hash::<Blake2bHash>());
+            assert!(manager.expected.expected.read().is_empty());
+
+            future::ok::<(), ()>(())
+        })).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+use futures::{Future, Poll, Async};
+use futures::future::Shared;
+use futures::sync::oneshot::{channel, Sender, Receiver};
+use parking_lot::Mutex;
+
+
+/// Future that resolves once the shutdown was triggered
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    inner: Shared<Receiver<()>>,
+}
+
+impl ShutdownSignal {
+    /// Runs `future` until it finishes or the shutdown is triggered, whichever comes first.
+    pub fn guard<F>(&self, future: F) -> Box<dyn Future<Item=(), Error=()> + Send>
+        where F: Future<Item=(), Error=()> + Send + 'static
+    {
+        Box::new(future.select(self.clone())
+            .map(|_| ())
+            .map_err(|_| ()))
+    }
+}
+
+impl Future for ShutdownSignal {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        match self.inner.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // The sender was either used or dropped. Both mean that we shut down.
+            _ => Ok(Async::Ready(())),
+        }
+    }
+}
+
+
+/// Triggers a `ShutdownSignal` that can be shared between many futures
+pub struct Shutdown {
+    sender: Mutex<Option<Sender<()>>>,
+    signal: ShutdownSignal,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel();
+        Self {
+            sender: Mutex::new(Some(sender)),
+            signal: ShutdownSignal {
+                inner: receiver.shared(),
+            },
+        }
+    }
+
+    /// Triggers the shutdown. Returns `false` if it was already triggered before.
+    pub fn trigger(&self) -> bool {
+        if let Some(sender) = self.sender.lock().take() {
+            // If all signals are gone already, there is nobody to notify.
+            sender.send(()).unwrap_or(());
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.sender.lock().is_none()
+    }
+
+    pub fn signal(&self) -> ShutdownSignal {
+        self.signal.clone()
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::{Future, future};
+
+    use super::Shutdown;
+
+    #[test]
+    fn test_trigger() {
+        let shutdown = Shutdown::new();
+        let signals = vec![shutdown.signal(), shutdown.signal()];
+
+        assert!(!shutdown.is_triggered());
+        assert!(shutdown.trigger());
+        assert!(shutdown.is_triggered());
+        assert!(!shutdown.trigger(), "Shutdown was triggered twice");
+
+        // all signals resolve, even ones that were created afterwards
+        for signal in signals {
+            assert!(signal.wait().is_ok());
+        }
+        assert!(shutdown.signal().wait().is_ok());
+    }
+
+    #[test]
+    fn test_guard() {
+        let shutdown = Shutdown::new();
+        let guarded = shutdown.signal().guard(future::empty::<(), ()>());
+
+        // the guarded future would never finish on its own
+        shutdown.trigger();
+        assert!(guarded.wait().is_ok());
+    }
+
+    #[test]
+    fn test_drop() {
+        let shutdown = Shutdown::new();
+        let signal = shutdown.signal();
+
+        // if the shutdown is gone, nobody can trigger it anymore, so we shut down
+        drop(shutdown);
+        assert!(signal.wait().is_ok());
+    }
+}
@@ -1,22 +1,53 @@
 use std::sync::Arc;
+use std::ops::Range;
+use std::collections::BTreeMap;
 
 use bls::bls12_381::Signature;
 use collections::bitset::BitSet;
+use hash::Blake2bHash;
 
 use crate::handel::MultiSignature;
-use crate::handel::BinomialPartitioner;
-use std::collections::BTreeMap;
+use crate::handel::{BinomialPartitioner, IdentityRegistry};
 
 
+/// Stores the verified signatures of an aggregation. Signatures are stored per value, i.e. per
+/// hash of the message that they sign.
 pub trait SignatureStore {
-    fn evaluate_individual(&self, individual: &Signature, level: usize, peer_id: usize) -> usize;
-    fn evaluate_multisig(&self, multisig: &MultiSignature, level: usize, votes: usize) -> usize;
+    fn evaluate_individual(&self, value: &Blake2bHash, individual: &Signature, level: usize, peer_id: usize) -> usize;
+    fn evaluate_multisig(&self, value: &Blake2bHash, multisig: &MultiSignature, level: usize, votes: usize) -> usize;
 
-    fn put_individual(&mut self, individual: Signature, level: usize, peer_id: usize);
-    fn put_multisig(&mut self, multisig: MultiSignature, level: usize);
+    fn put_individual(&mut self, value: Blake2bHash, individual: Signature, level: usize, peer_id: usize);
+    fn put_multisig(&mut self, value: Blake2bHash, multisig: MultiSignature, level: usize);
 
-    fn best(&self, level: usize) -> Option<&MultiSignature>;
-    fn combined(&self, level: usize) -> Option<MultiSignature>;
+    fn best(&self, value: &Blake2bHash, level: usize) -> Option<&MultiSignature>;
+    fn combined(&self, value: &Blake2bHash, level: usize) -> Option<MultiSignature>;
+}
+
+
+/// The verified signatures for one value
+#[derive(Clone, Debug)]
+struct ValueSignatures {
+    /// BitSets for all the individual signatures that we already verified. This contains all
+    /// slots of the validators.
+    /// level -> bitset
+    individual_verified: Vec<BitSet>,
+
+    /// All individual signatures
+    /// level -> ID -> Signature
+    individual_signatures: Vec<BTreeMap<usize, Signature>>,
+
+    /// The best MultiSignature at each level
+    multisig_best: BTreeMap<usize, MultiSignature>,
+}
+
+impl ValueSignatures {
+    fn new(num_levels: usize) -> Self {
+        Self {
+            individual_verified: vec![BitSet::new(); num_levels],
+            individual_signatures: vec![BTreeMap::new(); num_levels],
+            multisig_best: BTreeMap::new(),
+        }
+    }
 }
 
 
@@ -24,47 +55,144 @@ pub trait SignatureStore {
 pub struct ReplaceStore {
     partitioner: Arc<BinomialPartitioner>,
 
+    /// Identities, used to weigh signatures
+    identities: Arc<IdentityRegistry>,
+
+    /// The total weight of all identities per level
+    level_weights: Vec<usize>,
+
     best_level: usize,
 
     /// BitSet that contains the IDs of all individual signatures we already received
     individual_received: BitSet,
 
-    /// BitSets for all the individual signatures that we already verified
-    /// level -> bitset
-    individual_verified: Vec<BitSet>,
+    /// The verified signatures per value
+    /// value -> signatures
+    values: BTreeMap<Blake2bHash, ValueSignatures>,
 
-    /// All individual signatures
-    /// level -> ID -> Signature
-    individual_signatures: Vec<BTreeMap<usize, Signature>>,
+    /// Levels that are combined without a signature, e.g. our own level, if we don't sign
+    optional: BitSet,
 
-    /// The best MultiSignature at each level
-    multisig_best: BTreeMap<usize, MultiSignature>,
+    /// Whether individual signatures are collected, even if they don't improve the
+    /// multi-signatures
+    collect_individuals: bool,
+
+    /// Incremented whenever the store changes at a level. Scores for a level only need to be
+    /// recomputed when its version changed.
+    /// level -> version
+    versions: Vec<usize>,
 }
 
 
 impl ReplaceStore {
-    pub fn new(partitioner: Arc<BinomialPartitioner>) -> ReplaceStore {
+    pub fn new(partitioner: Arc<BinomialPartitioner>, identities: Arc<IdentityRegistry>) -> ReplaceStore {
         let n = partitioner.max_id + 1;
 
-        let mut individual_verified = Vec::with_capacity(partitioner.num_levels);
-        let mut individual_signatures = Vec::with_capacity(partitioner.num_levels);
-        for _ in 0..partitioner.num_levels {
-            individual_verified.push(BitSet::new());
-            individual_signatures.push(BTreeMap::new());
+        let mut level_weights = Vec::with_capacity(partitioner.num_levels);
+        for level in 0..partitioner.num_levels {
+            level_weights.push(partitioner.range(level)
+                .map(|ids| ids.filter_map(|id| identities.get_by_id(id))
+                    .map(|identity| identity.weight)
+                    .sum())
+                .unwrap_or(0));
         }
 
+        let versions = vec![0; partitioner.num_levels];
+
         ReplaceStore {
             partitioner,
+            identities,
+            level_weights,
             best_level: 0,
             individual_received: BitSet::with_capacity(n),
-            individual_verified,
-            individual_signatures,
-            multisig_best: BTreeMap::new(),
+            values: BTreeMap::new(),
+            optional: BitSet::new(),
+            collect_individuals: false,
+            versions,
         }
     }
 
-    fn check_merge(&self, multisig: &MultiSignature, level: usize) -> Option<MultiSignature> {
-        if let Some(best_multisig) = self.multisig_best.get(&level) {
+    /// Total weight of the signers of `multisig`
+    pub fn weight(&self, multisig: &MultiSignature) -> usize {
+        self.identities.weight(&multisig.signers)
+    }
+
+    /// Total weight of all identities at `level`
+    pub fn level_weight(&self, level: usize) -> usize {
+        self.level_weights.get(level).cloned().unwrap_or(0)
+    }
+
+    /// Version of the store at `level`. This changes whenever a signature is put at that level.
+    pub fn version(&self, level: usize) -> usize {
+        self.versions.get(level).cloned().unwrap_or(0)
+    }
+
+    /// Slots of the validator `peer_id`
+    fn slots(&self, peer_id: usize) -> Range<usize> {
+        self.identities.get_by_id(peer_id)
+            .map(|identity| identity.slots())
+            .unwrap_or(peer_id .. peer_id + 1)
+    }
+
+    /// The values for which we have verified signatures
+    pub fn values(&self) -> impl Iterator<Item=&Blake2bHash> {
+        self.values.keys()
+    }
+
+    /// Marks `level` as optional. Signatures are combined without it, if it's missing.
+    pub fn set_optional(&mut self, level: usize) {
+        self.optional.insert(level);
+    }
+
+    /// Scores individual signatures that we don't know yet, even if they don't improve the
+    /// multi-signatures, e.g. to interpolate a threshold signature from them
+    pub fn set_collect_individuals(&mut self) {
+        self.collect_individuals = true;
+    }
+
+    /// The verified individual signatures for `value` at `levels`
+    /// ID -> Signature
+    pub fn individuals(&self, value: &Blake2bHash, levels: Range<usize>) -> BTreeMap<usize, Signature> {
+        let mut individuals = BTreeMap::new();
+        if let Some(signatures) = self.values.get(value) {
+            for level in levels {
+                if let Some(level_signatures) = signatures.individual_signatures.get(level) {
+                    individuals.extend(level_signatures.iter().map(|(&id, individual)| (id, individual.clone())));
+                }
+            }
+        }
+        individuals
+    }
+
+    /// Whether we have a signature for any value at `level`
+    pub fn has_signatures(&self, level: usize) -> bool {
+        self.values.values()
+            .any(|signatures| signatures.multisig_best.contains_key(&level))
+    }
+
+    /// The signers of the best signatures of all values at `level`
+    pub fn signers(&self, level: usize) -> BitSet {
+        let mut signers = BitSet::new();
+        for best in self.values.values().filter_map(|signatures| signatures.multisig_best.get(&level)) {
+            signers = &signers | &best.signers;
+        }
+        signers
+    }
+
+    /// Whether the best signatures of all values at `level` together contain the total weight of
+    /// that level, i.e. we heard from every identity at that level
+    pub fn is_complete(&self, level: usize) -> bool {
+        self.has_signatures(level)
+            && self.identities.weight(&self.signers(level)) >= self.level_weight(level)
+    }
+
+    fn check_merge(&self, value: &Blake2bHash, multisig: &MultiSignature, level: usize) -> Option<MultiSignature> {
+        let signatures = match self.values.get(value) {
+            Some(signatures) => signatures,
+            None => return Some(multisig.clone()),
+        };
+
+        if let Some(best_multisig) = signatures.multisig_best.get(&level) {
             // try to combine
             let mut multisig = multisig.clone();
 
@@ -72,28 +200,31 @@ impl ReplaceStore {
             multisig.add_multisig(best_multisig)
                 .unwrap_or_else(|e| debug!("check_merge: combining multisigs failed: {}", e));
 
-            let individual_verified = self.individual_verified.get(level)
+            let individual_verified = signatures.individual_verified.get(level)
                 .unwrap_or_else(|| panic!("Individual verified signatures BitSet is missing for level {}", level));
 
             // the bits set here are verified individual signatures that can be added to `multisig`
             let complements = &(&multisig.signers & individual_verified) ^ individual_verified;
 
             // check that if we combine we get a better signature
-            if complements.len() + multisig.len() <= best_multisig.len() {
+            if self.identities.weight(&complements) + self.weight(&multisig) <= self.weight(best_multisig) {
                 // doesn't get better
                 None
             }
             else {
                 // put in the individual signatures
-                for id in complements.iter() {
-                    // get individual signature
-                    // TODO: Why do we need to store individual signatures per level?
-                    let individual = self.individual_signatures.get(level)
-                        .unwrap_or_else(|| panic!("Individual signatures missing for level {}", level))
-                        .get(&id).unwrap_or_else(|| panic!("Individual signature with ID {} missing for level {}", id, level));
+                // TODO: Why do we need to store individual signatures per level?
+                let individual_signatures = signatures.individual_signatures.get(level)
+                    .unwrap_or_else(|| panic!("Individual signatures missing for level {}", level));
+                for (&id, individual) in individual_signatures.iter() {
+                    // `complements` contains all slots of the validators, so we only need to check
+                    // the first one
+                    if !complements.contains(id) {
+                        continue;
+                    }
 
                     // merge individual signature into multisig
-                    multisig.add_individual(individual, id)
+                    multisig.add_slots(individual, self.slots(id))
                         .unwrap_or_else(|e| panic!("Individual signature form id={} can't be added to multisig: {}", id, e));
                 }
 
@@ -108,23 +239,36 @@ impl ReplaceStore {
 
 
 impl SignatureStore for ReplaceStore {
-    fn evaluate_individual(&self, individual: &Signature, level: usize, peer_id: usize) -> usize {
-        if self.individual_signatures.get(level)
-            .unwrap_or_else(|| panic!("No individual signatures for level {}", level))
-            .get(&peer_id).is_some() {
+    fn evaluate_individual(&self, value: &Blake2bHash, individual: &Signature, level: usize, peer_id: usize) -> usize {
+        let known = self.values.get(value)
+            .map(|signatures| signatures.individual_signatures.get(level)
+                .unwrap_or_else(|| panic!("No individual signatures for level {}", level))
+                .contains_key(&peer_id))
+            .unwrap_or(false);
+
+        if known {
             //debug!("Individual signature already known");
             0
         }
         else {
-            self.evaluate_multisig(&MultiSignature::from_individual(individual, peer_id), level, 1)
+            let votes = self.identities.get_by_id(peer_id)
+                .map(|identity| identity.weight)
+                .unwrap_or(0);
+            let score = self.evaluate_multisig(value, &MultiSignature::from_slots(individual, self.slots(peer_id)), level, votes);
+            if score == 0 && self.collect_individuals { 1 } else { score }
         }
     }
 
-    fn evaluate_multisig(&self, multisig: &MultiSignature, level: usize, votes: usize) -> usize {
-        // TODO: Signatures may have different weights and we could use that for scoring
+    fn evaluate_multisig(&self, value: &Blake2bHash, multisig: &MultiSignature, level: usize, votes: usize) -> usize {
+        let to_receive = self.level_weight(level);
+        let signatures = self.values.get(value);
+        let best_signature = signatures.and_then(|signatures| signatures.multisig_best.get(&level));
 
-        let to_receive = self.partitioner.size(level);
-        let best_signature = self.multisig_best.get(&level);
+        // check if we already heard from everyone at that level
+        if self.is_complete(level) {
+            //debug!("Level already complete");
+            return 0;
+        }
 
         if let Some(best_signature) = best_signature {
             /*debug!("This is node {}", self.partitioner.node_id);
@@ -132,12 +276,6 @@ impl SignatureStore for ReplaceStore {
             debug!("multisig = {:#?}", multisig);
             debug!("best_signature = {:#?}", best_signature);*/
 
-            // check if the best signature for that level is already complete
-            if to_receive == best_signature.len() {
-                //debug!("Best signature already complete");
-                return 0;
-            }
-
             // check if the best signature is better than the new one
             if best_signature.signers.is_superset(&multisig.signers) {
                 //debug!("Best signature is better");
@@ -145,83 +283,118 @@ impl SignatureStore for ReplaceStore {
             }
         }
 
-        let with_individuals = &multisig.signers
-            | self.individual_verified.get(level)
-            .unwrap_or_else(|| panic!("Missing level {}", level));
+        let no_individuals = BitSet::new();
+        let individual_verified = match signatures {
+            Some(signatures) => signatures.individual_verified.get(level)
+                .unwrap_or_else(|| panic!("Missing level {}", level)),
+            None => &no_individuals,
+        };
+        let with_individuals = &multisig.signers | individual_verified;
+
+        // weight of the verified individual signatures that we can add to `multisig`
+        let individuals_weight = self.identities.weight(&(&with_individuals ^ &multisig.signers));
 
-        let (new_total, added_sigs, combined_sigs) = if let Some(best_signature) = best_signature {
+        // NOTE: `new_total` and `added_weight` are weights, `combined_sigs` is the number of
+        //       signatures we need to aggregate.
+        let (new_total, added_weight, combined_sigs) = if let Some(best_signature) = best_signature {
+            let best_weight = self.weight(best_signature);
             if multisig.signers.intersection_size(&best_signature.signers) > 0 {
                 // can't merge
-                let new_total = with_individuals.len();
-                (new_total, new_total.saturating_sub(best_signature.len()), new_total - multisig.len())
+                let new_total = votes + individuals_weight;
+                (new_total, new_total.saturating_sub(best_weight), with_individuals.len() - multisig.len())
             }
             else {
                 let final_sig = &with_individuals | &best_signature.signers;
-                let new_total = final_sig.len();
+                let new_total = self.identities.weight(&final_sig);
                 let combined_sigs = (final_sig ^ (&best_signature.signers | &multisig.signers)).len();
-                (new_total, new_total - best_signature.len(), combined_sigs)
+                (new_total, new_total.saturating_sub(best_weight), combined_sigs)
             }
         }
         else {
             // best is the new signature with the individual signatures
-            let new_total = with_individuals.len();
-            (new_total, new_total, new_total - multisig.len())
+            let new_total = votes + individuals_weight;
+            (new_total, new_total, with_individuals.len() - multisig.len())
         };
 
-        //debug!("new_total={}, added_sigs={}, combined_sigs={}", new_total, added_sigs, combined_sigs);
+        //debug!("new_total={}, added_weight={}, combined_sigs={}", new_total, added_weight, combined_sigs);
 
-        if added_sigs == 0 {
+        if added_weight == 0 {
             // XXX return 1 for an individual signature
             if multisig.len() == 1 { 1 } else { 0 }
         }
-        else if new_total == to_receive {
-            1000000 - level * 10 - combined_sigs
+        else if new_total >= to_receive {
+            (1000000 - level * 10).saturating_sub(combined_sigs)
         }
         else {
-            100000 - level * 100 + added_sigs * 10 - combined_sigs
+            // added weight in per mille of the level's weight, so that the score doesn't depend on
+            // the scale of the weights
+            let added_permille = added_weight * 1000 / to_receive.max(1);
+            (100000 - level * 100 + added_permille * 10).saturating_sub(combined_sigs)
         }
     }
 
-    fn put_individual(&mut self, individual: Signature, level: usize, peer_id: usize) {
+    fn put_individual(&mut self, value: Blake2bHash, individual: Signature, level: usize, peer_id: usize) {
         //info!("Putting individual signature into store: level={}, id={}", level, peer_id);
 
-        let multisig = MultiSignature::from_individual(&individual, peer_id);
+        let slots = self.slots(peer_id);
+        let multisig = MultiSignature::from_slots(&individual, slots.clone());
 
-        self.individual_verified.get_mut(level)
-            .unwrap_or_else(|| panic!("Missing level {}", level))
-            .insert(peer_id);
+        let num_levels = self.partitioner.num_levels;
+        let signatures = self.values.entry(value.clone())
+            .or_insert_with(|| ValueSignatures::new(num_levels));
 
-        self.individual_signatures.get_mut(level)
+        let individual_verified = signatures.individual_verified.get_mut(level)
+            .unwrap_or_else(|| panic!("Missing level {}", level));
+        for slot in slots {
+            individual_verified.insert(slot);
+        }
+
+        signatures.individual_signatures.get_mut(level)
             .unwrap_or_else(|| panic!("Missing level {}", level))
             .insert(peer_id, individual);
 
-        self.put_multisig(multisig, level)
+        self.versions[level] += 1;
+
+        self.put_multisig(value, multisig, level)
     }
 
-    fn put_multisig(&mut self, multisig: MultiSignature, level: usize) {
+    fn put_multisig(&mut self, value: Blake2bHash, multisig: MultiSignature, level: usize) {
         //info!("Putting multi-signature into store: level={}, ids={}", level, multisig.signers);
 
-        if let Some(best_multisig) = self.check_merge(&multisig, level) {
+        if let Some(best_multisig) = self.check_merge(&value, &multisig, level) {
             //debug!("Changing best multisig for level {}: signers={}", level, multisig.signers);
-            self.multisig_best.insert(level, best_multisig);
+            let num_levels = self.partitioner.num_levels;
+            self.values.entry(value)
+                .or_insert_with(|| ValueSignatures::new(num_levels))
+                .multisig_best.insert(level, best_multisig);
+            self.versions[level] += 1;
             if level > self.best_level {
                 self.best_level = level;
             }
         }
     }
 
-    fn best(&self, level: usize) -> Option<&MultiSignature> {
-        self.multisig_best.get(&level)
+    fn best(&self, value: &Blake2bHash, level: usize) -> Option<&MultiSignature> {
+        self.values.get(value)?.multisig_best.get(&level)
     }
 
-    fn combined(&self, mut level: usize) -> Option<MultiSignature> {
+    fn combined(&self, value: &Blake2bHash, mut level: usize) -> Option<MultiSignature> {
+        let multisig_best = &self.values.get(value)?.multisig_best;
+
         let mut signatures = Vec::new();
-        for (&i, signature) in self.multisig_best.range(0 ..= level) {
-            if i > signatures.len()  {
-                //warn!("MultiSignature missing for level {} to {}", signatures.len(), i - 1);
-                return None;
+        for i in 0 ..= level {
+            match multisig_best.get(&i) {
+                Some(signature) => signatures.push(signature),
+                // levels without any validators don't contribute to the signature
+                None if self.level_weight(i) == 0 => {},
+                // the identities at this level signed other values
+                None if self.has_signatures(i) => {},
+                None if self.optional.contains(i) => {},
+                None => {
+                    //warn!("MultiSignature missing for level {}", i);
+                    return None;
+                },
             }
-            signatures.push(signature)
         }
 
         // ???
@@ -0,0 +1,32 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use rand::SeedableRng;
+use rand_chacha::ChaChaRng;
+
+use bls::bls12_381::KeyPair;
+
+use crate::handel::{Identity, IdentityRegistry};
+
+
+/// The address of the node with this index
+pub fn address(id: usize) -> SocketAddr {
+    SocketAddr::new("127.0.0.1".parse().unwrap(), (12000 + id) as u16)
+}
+
+/// Creates the key pairs of `num_nodes` nodes. They're the same in every run.
+pub fn create_key_pairs(num_nodes: usize) -> Vec<KeyPair> {
+    let mut csprng = ChaChaRng::from_seed([42; 32]);
+    (0 .. num_nodes)
+        .map(|_| KeyPair::generate(&mut csprng))
+        .collect()
+}
+
+/// Creates a committee in which the node with index `i` owns slot `i` and has the `i`-th key pair
+pub fn create_identities(key_pairs: &[KeyPair]) -> IdentityRegistry {
+    let mut registry = IdentityRegistry::new();
+    for (id, key_pair) in key_pairs.iter().enumerate() {
+        registry.insert(Arc::new(Identity::new(id, key_pair.public.clone(), address(id), 1))).unwrap();
+    }
+    registry
+}
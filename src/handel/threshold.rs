@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use failure::Fail;
+use pairing::{CurveProjective, Field, PrimeField};
+use pairing::bls12_381::{Fr, FrRepr, G1, G2};
+use rand::{Rng, CryptoRng};
+
+use beserial::{Serialize, Deserialize};
+use bls::bls12_381::{KeyPair, SecretKey, PublicKey, Signature};
+use hash::Blake2bHash;
+
+use crate::handel::IdentityRegistry;
+
+
+/// Maximum threshold of a threshold key
+///
+/// Every contribution carries up to `threshold` partial signatures of about 50 bytes each, and the
+/// network codec only accepts packets of up to 1024 bytes. The rest of the packet is needed for
+/// the multi-signature with its signers and the signature of the origin.
+///
+/// This limits the threshold mode to small committees: With a threshold of more than two thirds
+/// of the committee, a committee can have at most 11 validators.
+pub const MAX_THRESHOLD: usize = 8;
+
+
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum ThresholdError {
+    #[fail(display = "Invalid threshold: {}-of-{}", _0, _1)]
+    InvalidThreshold(usize, usize),
+    #[fail(display = "Threshold {} exceeds MAX_THRESHOLD", _0)]
+    ThresholdTooLarge(usize),
+    #[fail(display = "Too many shares: {}", _0)]
+    TooManyShares(usize),
+    #[fail(display = "Validator {} doesn't own exactly one slot", _0)]
+    MultipleSlots(usize),
+}
+
+
+/// A partial signature of a threshold key, i.e. a signature with a key share
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PartialSignature {
+    /// ID of the validator that owns the key share
+    pub signer: u16,
+    pub signature: Signature,
+}
+
+impl PartialSignature {
+    /// Returns `None`, if the ID of the signer doesn't fit into a message
+    pub fn new(signer: usize, signature: Signature) -> Option<Self> {
+        Some(Self {
+            signer: u16::try_from(signer).ok()?,
+            signature,
+        })
+    }
+}
+
+
+/// The public part of a t-of-n threshold key
+///
+/// The validator with ID `i` owns the share of the secret key at `x = i + 1`. Any `threshold`
+/// partial signatures can be interpolated into a signature that verifies against
+/// `group_public_key`. Shares are assigned per validator, so every validator must own exactly
+/// one slot. The threshold must not exceed `MAX_THRESHOLD`, which `check` makes sure of.
+#[derive(Clone, Debug)]
+pub struct ThresholdKey {
+    pub group_public_key: PublicKey,
+
+    /// Number of partial signatures that are needed for a signature
+    pub threshold: usize,
+}
+
+impl ThresholdKey {
+    /// Interpolates the signature of the group from the first `threshold` partial signatures.
+    /// Returns `None`, if there are not enough partial signatures. The partial signatures must be
+    /// verified already.
+    pub fn combine(&self, partials: &BTreeMap<usize, Signature>) -> Option<Signature> {
+        if self.threshold == 0 || partials.len() < self.threshold {
+            return None;
+        }
+
+        let partials = partials.iter()
+            .take(self.threshold)
+            .collect::<Vec<(&usize, &Signature)>>();
+        let ids = partials.iter()
+            .map(|(&id, _)| id)
+            .collect::<Vec<usize>>();
+
+        let mut signature = Signature { s: G1::zero() };
+        for (&id, partial) in partials {
+            let mut s = partial.s;
+            s.mul_assign(lagrange_coefficient(id, &ids));
+            signature.s.add_assign(&s);
+        }
+
+        Some(signature)
+    }
+
+    pub fn verify(&self, message_hash: Blake2bHash, signature: &Signature) -> bool {
+        self.group_public_key.verify_hash(message_hash, signature)
+    }
+
+    /// Checks that the key can be used with the committee `identities`, i.e. that the threshold
+    /// doesn't exceed `MAX_THRESHOLD` and that every validator owns exactly one slot
+    pub fn check(&self, identities: &IdentityRegistry) -> Result<(), ThresholdError> {
+        if self.threshold == 0 || self.threshold > identities.len() {
+            return Err(ThresholdError::InvalidThreshold(self.threshold, identities.len()));
+        }
+        if self.threshold > MAX_THRESHOLD {
+            return Err(ThresholdError::ThresholdTooLarge(self.threshold));
+        }
+        if let Some(identity) = identities.all().iter().find(|identity| identity.weight != 1) {
+            return Err(ThresholdError::MultipleSlots(identity.id));
+        }
+        Ok(())
+    }
+}
+
+
+/// The key shares of a threshold key, as created by a trusted dealer
+#[derive(Clone, Debug)]
+pub struct ThresholdSetup {
+    pub key: ThresholdKey,
+
+    /// The key pair of every share. The share of the validator with ID `i` is at index `i`.
+    pub shares: Vec<KeyPair>,
+}
+
+impl ThresholdSetup {
+    /// Creates a `threshold`-of-`num_shares` key. The dealer knows the secret key of the group,
+    /// so it must be trusted to forget it.
+    pub fn deal<R: Rng + CryptoRng>(threshold: usize, num_shares: usize, csprng: &mut R) -> Result<Self, ThresholdError> {
+        if threshold == 0 || threshold > num_shares {
+            return Err(ThresholdError::InvalidThreshold(threshold, num_shares));
+        }
+        if threshold > MAX_THRESHOLD {
+            return Err(ThresholdError::ThresholdTooLarge(threshold));
+        }
+        // the ID of every share must fit into a partial signature
+        if num_shares > 1 << 16 {
+            return Err(ThresholdError::TooManyShares(num_shares));
+        }
+
+        // random polynomial of degree `threshold - 1`. The secret key of the group is its value
+        // at 0.
+        let coefficients = (0 .. threshold)
+            .map(|_| SecretKey::generate(csprng).x)
+            .collect::<Vec<Fr>>();
+
+        let shares = (0 .. num_shares)
+            .map(|id| key_pair(evaluate(&coefficients, x_coordinate(id))))
+            .collect();
+
+        Ok(ThresholdSetup {
+            key: ThresholdKey {
+                group_public_key: key_pair(coefficients[0]).public,
+                threshold,
+            },
+            shares,
+        })
+    }
+}
+
+
+/// The x-coordinate of the key share of the validator `id`
+fn x_coordinate(id: usize) -> Fr {
+    Fr::from_repr(FrRepr::from(id as u64 + 1))
+        .expect("Validator ID is not a field element")
+}
+
+/// Evaluates the polynomial with the `coefficients` at `x` with Horner's method
+fn evaluate(coefficients: &[Fr], x: Fr) -> Fr {
+    let mut y = Fr::zero();
+    for coefficient in coefficients.iter().rev() {
+        y.mul_assign(&x);
+        y.add_assign(coefficient);
+    }
+    y
+}
+
+/// The Lagrange coefficient of the share of `id` for interpolating at 0 from the shares of `ids`
+fn lagrange_coefficient(id: usize, ids: &[usize]) -> Fr {
+    let x_i = x_coordinate(id);
+    let mut numerator = Fr::one();
+    let mut denominator = Fr::one();
+
+    for &other in ids.iter().filter(|&&other| other != id) {
+        let x_j = x_coordinate(other);
+        numerator.mul_assign(&x_j);
+
+        let mut difference = x_j;
+        difference.sub_assign(&x_i);
+        denominator.mul_assign(&difference);
+    }
+
+    let inverse = denominator.inverse()
+        .expect("Key shares must have distinct IDs");
+    numerator.mul_assign(&inverse);
+    numerator
+}
+
+fn key_pair(x: Fr) -> KeyPair {
+    let mut p_pub = G2::one();
+    p_pub.mul_assign(x);
+
+    KeyPair {
+        secret: SecretKey { x },
+        public: PublicKey { p_pub },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
+
+    use hash::{Hash, Blake2bHash};
+
+    use super::{ThresholdSetup, ThresholdError};
+
+    #[test]
+    fn test_interpolation() {
+        let mut csprng = ChaChaRng::from_seed([42; 32]);
+        let setup = ThresholdSetup::deal(3, 5, &mut csprng).unwrap();
+        let message_hash = "foobar".hash::<Blake2bHash>();
+
+        let partials = |ids: &[usize]| {
+            ids.iter()
+                .map(|&id| (id, setup.shares[id].sign_hash(message_hash.clone())))
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        // any 3 shares produce the signature of the group
+        for ids in &[[0, 1, 2], [0, 2, 4], [1, 3, 4]] {
+            let signature = setup.key.combine(&partials(ids)).unwrap();
+            assert!(setup.key.verify(message_hash.clone(), &signature));
+        }
+
+        // 2 shares are not enough
+        assert!(setup.key.combine(&partials(&[1, 3])).is_none());
+    }
+
+    #[test]
+    fn test_invalid_threshold() {
+        let mut csprng = ChaChaRng::from_seed([42; 32]);
+        assert_eq!(ThresholdSetup::deal(6, 5, &mut csprng).err(), Some(ThresholdError::InvalidThreshold(6, 5)));
+        assert_eq!(ThresholdSetup::deal(9, 12, &mut csprng).err(), Some(ThresholdError::ThresholdTooLarge(9)));
+    }
+}
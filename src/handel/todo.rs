@@ -0,0 +1,162 @@
+use std::cmp::Reverse;
+use std::sync::Arc;
+
+use bls::bls12_381::Signature;
+use hash::Blake2bHash;
+
+use crate::handel::{MultiSignature, SignatureStore, ReplaceStore, Message};
+
+
+#[derive(Clone, Debug)]
+pub enum Todo {
+    /// An individual signature of `origin`. `message` is the message that contained it, which is
+    /// kept as evidence in case the signature is invalid. It's `None` for our own signature. With a
+    /// threshold key, the origin of `message` might have relayed the partial signature of
+    /// `origin`.
+    ///
+    /// `authenticated` is set, if the message came from the address of its origin. Only then an
+    /// invalid signature counts against the origin of the message.
+    Individual { value: Blake2bHash, signature: Signature, level: usize, origin: usize, message: Option<Arc<Message>>, authenticated: bool },
+    Multi { value: Blake2bHash, signature: MultiSignature, level: usize, votes: usize, origin: usize, authenticated: bool }
+}
+
+impl Todo {
+    pub fn evaluate(&self, store: &ReplaceStore) -> usize {
+        match self {
+            Todo::Multi { value, signature, level, votes, .. } => store.evaluate_multisig(value, signature, *level, *votes),
+            Todo::Individual { value, signature, level, origin, .. } => store.evaluate_individual(value, signature, *level, *origin)
+        }
+    }
+
+    pub fn put(self, store: &mut ReplaceStore) {
+        match self {
+            Todo::Individual { value, signature, level, origin, .. } => {
+                store.put_individual(value, signature, level, origin)
+            }
+            Todo::Multi { value, signature, level, .. } => {
+                store.put_multisig(value, signature, level)
+            }
+        }
+    }
+
+    pub fn level(&self) -> usize {
+        *match self {
+            Todo::Individual { level, .. } => level,
+            Todo::Multi { level, .. } => level,
+        }
+    }
+
+    /// Hash of the value that the signature signs
+    pub fn value(&self) -> &Blake2bHash {
+        match self {
+            Todo::Individual { value, .. } => value,
+            Todo::Multi { value, .. } => value,
+        }
+    }
+
+    /// The peer that sent this TODO
+    pub fn origin(&self) -> usize {
+        *match self {
+            Todo::Individual { origin, .. } => origin,
+            Todo::Multi { origin, .. } => origin,
+        }
+    }
+}
+
+
+/// The TODOs of one level, ordered by their score
+///
+/// TODOs are contributions that weren't verified yet. They are scored before verification, so
+/// that we only spend time on verifying contributions that improve the store. Among TODOs with the
+/// same score, the ones from peers with a better rank are preferred.
+///
+/// Scores only depend on the store's state at the same level. Thus the TODOs are only re-scored
+/// when the store's version for that level changed. TODOs that can't improve the store anymore are
+/// dropped then.
+#[derive(Clone, Debug)]
+pub struct TodoQueue {
+    level: usize,
+
+    /// Maximum number of TODOs. If more are pushed, the worst ones are dropped.
+    capacity: usize,
+
+    /// Version of the store when the TODOs were scored
+    version: usize,
+
+    /// TODOs with the rank of their sender and their score, sorted by priority (best first)
+    todos: Vec<(usize, usize, Todo)>,
+}
+
+impl TodoQueue {
+    pub fn new(level: usize, capacity: usize) -> Self {
+        Self {
+            level,
+            capacity,
+            version: 0,
+            todos: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.todos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.todos.is_empty()
+    }
+
+    /// Lower is better: the best score first, ties are broken by the rank
+    fn priority(rank: usize, score: usize) -> (Reverse<usize>, usize) {
+        (Reverse(score), rank)
+    }
+
+    /// Queues a TODO that was sent by the peer with `rank` at this level
+    pub fn push(&mut self, todo: Todo, rank: usize, store: &ReplaceStore) {
+        assert_eq!(todo.level(), self.level, "TODO for level {} pushed to queue for level {}", todo.level(), self.level);
+
+        self.rescore(store);
+
+        let score = todo.evaluate(store);
+        if score == 0 {
+            // can't improve the store
+            return;
+        }
+
+        let priority = Self::priority(rank, score);
+        let i = self.todos.binary_search_by_key(&priority, |(rank, score, _)| Self::priority(*rank, *score))
+            .unwrap_or_else(|i| i);
+        self.todos.insert(i, (rank, score, todo));
+
+        if self.todos.len() > self.capacity {
+            // drop the worst TODO
+            self.todos.pop();
+        }
+    }
+
+    /// Removes the best TODO for which `f` returns `true` and returns it with its score. `f` is
+    /// called with the rank of the TODO's sender.
+    pub fn pop_best_by<F>(&mut self, store: &ReplaceStore, f: F) -> Option<(Todo, usize)>
+        where F: Fn(usize, &Todo) -> bool
+    {
+        self.rescore(store);
+        let i = self.todos.iter().position(|(rank, _, todo)| f(*rank, todo))?;
+        let (_, score, todo) = self.todos.remove(i);
+        Some((todo, score))
+    }
+
+    /// Re-scores all TODOs, if the store changed at our level
+    pub fn rescore(&mut self, store: &ReplaceStore) {
+        let version = store.version(self.level);
+        if version == self.version {
+            return;
+        }
+
+        for (_, score, todo) in self.todos.iter_mut() {
+            *score = todo.evaluate(store);
+        }
+        self.todos.retain(|(_, score, _)| *score > 0);
+        self.todos.sort_by_key(|(rank, score, _)| Self::priority(*rank, *score));
+
+        self.version = version;
+    }
+}
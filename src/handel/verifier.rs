@@ -23,30 +23,80 @@ pub enum VerifyResult {
 pub trait Verifier {
     type Output: Future<Item=VerifyResult, Error=()>;
 
-    fn verify_individual(&self, signature: Signature, signer: usize) -> Self::Output;
-    fn verify_multisig(&self, signature: MultiSignature, check_threshold: bool) -> Self::Output;
+    /// Verifies that `signer` signed `message_hash`
+    fn verify_individual(&self, message_hash: Blake2bHash, signature: Signature, signer: usize) -> Self::Output;
+
+    /// Verifies that the signers of `signature` signed `message_hash`
+    fn verify_multisig(&self, message_hash: Blake2bHash, signature: MultiSignature, check_threshold: bool) -> Self::Output;
+}
+
+
+/// Future returned by type-erased verifiers
+pub type VerifyFuture = Box<dyn Future<Item=VerifyResult, Error=()> + Send>;
+
+/// A verifier whose backend is chosen at runtime
+pub type BoxVerifier = Box<dyn Verifier<Output=VerifyFuture> + Send + Sync>;
+
+
+/// Wraps a verifier and boxes its output futures, so that it can be used as a `BoxVerifier`.
+pub struct Boxed<V> {
+    inner: V,
+}
+
+impl<V> Boxed<V>
+    where V: Verifier + Send + Sync + 'static,
+          V::Output: Send + 'static
+{
+    pub fn new(inner: V) -> Self {
+        Self {
+            inner,
+        }
+    }
+
+    pub fn boxed(inner: V) -> BoxVerifier {
+        Box::new(Self::new(inner))
+    }
+}
+
+impl<V> Verifier for Boxed<V>
+    where V: Verifier,
+          V::Output: Send + 'static
+{
+    type Output = VerifyFuture;
+
+    fn verify_individual(&self, message_hash: Blake2bHash, signature: Signature, signer: usize) -> Self::Output {
+        Box::new(self.inner.verify_individual(message_hash, signature, signer))
+    }
+
+    fn verify_multisig(&self, message_hash: Blake2bHash, signature: MultiSignature, check_threshold: bool) -> Self::Output {
+        Box::new(self.inner.verify_multisig(message_hash, signature, check_threshold))
+    }
 }
 
 
 pub struct ThreadPoolVerifier {
     threshold: usize,
-    message_hash: Blake2bHash,
     identities: Arc<IdentityRegistry>,
     workers: CpuPool,
 }
 
 
 impl ThreadPoolVerifier {
-    pub fn new(threshold: usize, message_hash: Blake2bHash, identities: Arc<IdentityRegistry>, num_workers: Option<usize>) -> Self {
+    pub fn new(threshold: usize, identities: Arc<IdentityRegistry>, num_workers: Option<usize>) -> Self {
         let workers = if let Some(n) = num_workers {
             CpuPool::new(n)
         } else {
             CpuPool::new_num_cpus()
         };
 
+        Self::with_pool(threshold, identities, workers)
+    }
+
+    /// Creates a verifier that runs on an existing thread pool. This way multiple verifiers can
+    /// share their worker threads.
+    pub fn with_pool(threshold: usize, identities: Arc<IdentityRegistry>, workers: CpuPool) -> Self {
         Self {
             threshold,
-            message_hash,
             identities,
             workers,
         }
@@ -56,8 +106,7 @@ impl ThreadPoolVerifier {
 impl Verifier for ThreadPoolVerifier {
     type Output = CpuFuture<VerifyResult, ()>;
 
-    fn verify_individual(&self, signature: Signature, signer: usize) -> Self::Output {
-        let message_hash = self.message_hash.clone();
+    fn verify_individual(&self, message_hash: Blake2bHash, signature: Signature, signer: usize) -> Self::Output {
         let identities = Arc::clone(&self.identities);
 
         self.workers.spawn_fn(move || {
@@ -65,7 +114,7 @@ impl Verifier for ThreadPoolVerifier {
 
             let result = if let Some(identity) = identities.get_by_id(signer) {
                 if identity.public_key.verify_hash(message_hash, &signature) {
-                    VerifyResult::Ok { votes: 1 }
+                    VerifyResult::Ok { votes: identity.weight }
                 }
                 else {
                     VerifyResult::InvalidSignature
@@ -82,9 +131,8 @@ impl Verifier for ThreadPoolVerifier {
         })
     }
 
-    fn verify_multisig(&self, signature: MultiSignature, check_threshold: bool) -> Self::Output {
+    fn verify_multisig(&self, message_hash: Blake2bHash, signature: MultiSignature, check_threshold: bool) -> Self::Output {
         let identities = Arc::clone(&self.identities);
-        let message_hash = self.message_hash.clone();
         let threshold = self.threshold;
 
         self.workers.spawn_fn(move || {
@@ -93,14 +141,15 @@ impl Verifier for ThreadPoolVerifier {
             let mut public_key = AggregatePublicKey::new();
             let mut votes = 0;
 
-            for signer in signature.signers.iter() {
-                if let Some(identity) = identities.get_by_id(signer) {
-                    public_key.aggregate(&identity.public_key);
-                    votes += identity.weight;
-                }
-                else {
-                    return future::ok(VerifyResult::UnknownSigner { signer });
-                }
+            // every validator signed once for all its slots
+            match identities.signers(&signature.signers) {
+                Ok(signers) => {
+                    for identity in signers {
+                        public_key.aggregate(&identity.public_key);
+                        votes += identity.weight;
+                    }
+                },
+                Err(signer) => return future::ok(VerifyResult::UnknownSigner { signer }),
             }
 
             let result = if check_threshold && votes < threshold {
@@ -139,25 +188,23 @@ impl DummyVerifier {
 impl Verifier for DummyVerifier {
     type Output = FutureResult<VerifyResult, ()>;
 
-    fn verify_individual(&self, signature: Signature, signer: usize) -> Self::Output {
-        Ok(VerifyResult::Ok { votes: 1 }).into()
+    fn verify_individual(&self, _message_hash: Blake2bHash, _signature: Signature, signer: usize) -> Self::Output {
+        let result = match self.identities.get_by_id(signer) {
+            Some(identity) => VerifyResult::Ok { votes: identity.weight },
+            None => VerifyResult::UnknownSigner { signer },
+        };
+        Ok(result).into()
     }
 
-    fn verify_multisig(&self, signature: MultiSignature, check_threshold: bool) -> Self::Output {
-        let mut votes = 0;
-
-        for signer in signature.signers.iter() {
-            if let Some(identity) = self.identities.get_by_id(signer) {
-                votes += identity.weight;
-            }
-            else {
-                return future::ok(VerifyResult::UnknownSigner { signer });
-            }
-        }
+    fn verify_multisig(&self, _message_hash: Blake2bHash, signature: MultiSignature, check_threshold: bool) -> Self::Output {
+        let votes = match self.identities.signers(&signature.signers) {
+            Ok(signers) => signers.iter().map(|identity| identity.weight).sum(),
+            Err(signer) => return future::ok(VerifyResult::UnknownSigner { signer }),
+        };
 
         let result = if check_threshold && votes < self.threshold {
             VerifyResult::ThresholdNotReached {
-                votes: signature.len(),
+                votes,
                 threshold: self.threshold
             }
         }
@@ -169,14 +216,14 @@ impl Verifier for DummyVerifier {
     }
 }
 
-/*impl<V: Verifier + ?Sized> Verifier for Box<V> {
+impl<V: Verifier + ?Sized> Verifier for Box<V> {
     type Output = <V as Verifier>::Output;
 
-    fn verify_individual(&self, signature: Signature, signer: usize) -> Self::Output {
-        (**self).verify_individual(signature, signer)
+    fn verify_individual(&self, message_hash: Blake2bHash, signature: Signature, signer: usize) -> Self::Output {
+        (**self).verify_individual(message_hash, signature, signer)
     }
 
-    fn verify_multisig(&self, signature: MultiSignature, check_threshold: bool) -> Self::Output {
-        (**self).verify_multisig(signature, check_threshold)
+    fn verify_multisig(&self, message_hash: Blake2bHash, signature: MultiSignature, check_threshold: bool) -> Self::Output {
+        (**self).verify_multisig(message_hash, signature, check_threshold)
     }
-}*/
+}
@@ -0,0 +1,76 @@
+/// Verification window of a level
+///
+/// Only contributions from peers whose rank is within the window are verified. The window starts
+/// at the best rank whose contribution we didn't verify yet, so that it slides over the ranks as
+/// contributions are verified. Pending contributions don't move the window, so a peer can't pull
+/// it towards itself. It is doubled after a successful verification and divided by 4
+/// after a failed one.
+#[derive(Clone, Debug)]
+pub struct VerificationWindow {
+    size: usize,
+    min: usize,
+    max: usize,
+}
+
+impl VerificationWindow {
+    pub fn new(initial: usize, min: usize, max: usize) -> Self {
+        assert!(min >= 1, "Minimum window size must be at least 1");
+        assert!(min <= max, "Minimum window size is larger than maximum window size");
+
+        Self {
+            size: initial.max(min).min(max),
+            min,
+            max,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether a contribution from a peer with `rank` is admitted, if the best rank that we didn't
+    /// verify yet is `start`.
+    pub fn admits(&self, start: usize, rank: usize) -> bool {
+        rank < start + self.size
+    }
+
+    pub fn on_success(&mut self) {
+        self.size = (self.size * 2).min(self.max);
+    }
+
+    pub fn on_failure(&mut self) {
+        self.size = (self.size / 4).max(self.min);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::VerificationWindow;
+
+    #[test]
+    fn test_window_adapts() {
+        let mut window = VerificationWindow::new(4, 1, 16);
+
+        window.on_success();
+        assert_eq!(window.size(), 8);
+        window.on_success();
+        window.on_success();
+        assert_eq!(window.size(), 16, "Window must not grow beyond maximum");
+
+        window.on_failure();
+        assert_eq!(window.size(), 4);
+        window.on_failure();
+        window.on_failure();
+        assert_eq!(window.size(), 1, "Window must not shrink below minimum");
+    }
+
+    #[test]
+    fn test_window_admits() {
+        let window = VerificationWindow::new(4, 1, 16);
+
+        assert!(window.admits(2, 2));
+        assert!(window.admits(2, 5));
+        assert!(!window.admits(2, 6));
+    }
+}
@@ -0,0 +1,36 @@
+#[macro_use]
+extern crate log;
+extern crate tokio;
+extern crate futures;
+extern crate bytes;
+extern crate failure;
+extern crate futures_cpupool;
+extern crate tokio_timer;
+extern crate rand_chacha;
+extern crate stopwatch;
+
+extern crate beserial;
+#[macro_use]
+extern crate beserial_derive;
+extern crate nimiq_bls as bls;
+extern crate nimiq_collections as collections;
+extern crate nimiq_hash as hash;
+extern crate nimiq_block_albatross as block;
+extern crate pairing;
+
+
+mod handel;
+
+
+// The facade: run or observe an aggregation on its own network
+pub use crate::handel::{
+    aggregate, observe, pbft_aggregate, AggregateParams, AggregateError, PbftPhase, PbftError,
+    Identity, IdentityRegistry, IdentityError, MultiSignature, FinalSignature, AggregationError,
+    VerifierBackend, Evidence, Misbehaviour,
+    ThresholdKey, ThresholdSetup, ThresholdError, MAX_THRESHOLD,
+};
+
+// Agents on a network that is set up by the caller, e.g. for the testnet
+pub use crate::handel::{
+    Config, HandelAgent, AgentProcessor, HandelResult, UdpNetwork, Shutdown, Hierarchy, TierRouter,
+};
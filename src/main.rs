@@ -5,24 +5,17 @@ extern crate simple_logger;
 extern crate clap;
 extern crate tokio;
 extern crate futures;
-extern crate bytes;
 extern crate failure;
 extern crate hex;
-extern crate futures_cpupool;
-extern crate tokio_timer;
 extern crate rand_chacha;
 extern crate stopwatch;
 
 extern crate beserial;
-#[macro_use]
-extern crate beserial_derive;
 extern crate nimiq_bls as bls;
-extern crate nimiq_collections as collections;
 extern crate nimiq_hash as hash;
 extern crate nimiq_block_albatross as block;
 
 
-mod handel;
 mod testnet;
 
 
@@ -30,102 +23,185 @@ use std::io::Error as IoError;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
+use std::fs;
 
-use futures::{Future, future};
+use futures::Future;
 use log::Level;
 use clap::{App, Arg};
 use failure::Error;
 
-use beserial::Deserialize;
+use beserial::{Serialize, Deserialize};
 use hash::{Hash, Blake2bHash};
-use bls::bls12_381::{KeyPair};
+use bls::bls12_381::{KeyPair, PublicKey};
+use block::MacroHeader;
+
+use handel::{Identity, IdentityRegistry, VerifierBackend, AggregateParams, ThresholdKey, aggregate, observe, pbft_aggregate};
 
-use crate::handel::{UdpNetwork, HandelAgent, Config, Identity, AgentProcessor, IdentityRegistry};
 use crate::testnet::TestNet;
 
 
+/// Loads the committee from a file that contains one hex-encoded, serialized identity per line.
+/// Empty lines and lines starting with `#` are skipped.
+fn load_committee(path: &str) -> Result<IdentityRegistry, Error> {
+    let mut committee = IdentityRegistry::new();
+
+    for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let identity: Identity = Deserialize::deserialize_from_vec(&hex::decode(line)?)
+            .map_err(|e| failure::err_msg(format!("Invalid identity in {}, line {}: {}", path, i + 1, IoError::from(e))))?;
+        committee.insert(Arc::new(identity))
+            .map_err(|e| failure::err_msg(format!("Invalid identity in {}, line {}: {}", path, i + 1, e)))?;
+    }
+
+    if committee.len() == 0 {
+        return Err(failure::err_msg(format!("Committee file {} contains no identities", path)));
+    }
+
+    Ok(committee)
+}
+
+
 fn run_app() -> Result<(), Error> {
     // parse command line
     let matches = App::new(crate_name!())
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
-        .arg(Arg::with_name("id")
-            .long("id")
-            .value_name("ID")
-            .takes_value(true)
-            .required(true))
         .arg(Arg::with_name("secret_key")
             .long("secret-key")
             .value_name("SECRETKEY")
             .takes_value(true)
-            .required(false /* true */))
-        .arg(Arg::with_name("address")
-            .long("address")
-            .value_name("ADDRESS")
+            .required_unless("observe"))
+        .arg(Arg::with_name("committee")
+            .long("committee")
+            .value_name("FILE")
             .takes_value(true)
-            .default_value("127.0.0.1:1337"))
+            .required(true)
+            .help("File with the identities of the committee, one hex-encoded identity per line"))
         .arg(Arg::with_name("port")
             .long("port")
             .value_name("PORT")
             .takes_value(true)
-            .default_value("1337"))
+            .help("Port to listen on (default: port of our identity)"))
         .arg(Arg::with_name("threshold")
             .long("threshold")
             .value_name("THRESHOLD")
             .takes_value(true)
-            .required(false /* true */))
+            .help("Minimum weight needed for a valid signature. A signature with exactly this weight is valid. (default: more than 2/3 of the committee)"))
         .arg(Arg::with_name("message")
             .long("message")
             .value_name("MESSAGE")
             .takes_value(true)
-            .required(false /* true */))
+            .conflicts_with("block")
+            .required_unless("block")
+            .help("Message to sign"))
+        .arg(Arg::with_name("block")
+            .long("block")
+            .value_name("HEX")
+            .takes_value(true)
+            .help("Serialized header of the macro block to prepare and commit"))
+        .arg(Arg::with_name("workers")
+            .long("workers")
+            .value_name("NUM")
+            .takes_value(true)
+            .help("Number of threads used for signature verification (default: number of CPUs)"))
+        .arg(Arg::with_name("extra_time")
+            .long("extra-time")
+            .value_name("MS")
+            .takes_value(true)
+            .help("Keep improving the signature for this long after reaching the threshold"))
+        .arg(Arg::with_name("catch_up")
+            .long("catch-up")
+            .help("Start all levels immediately and ask peers for their aggregates, e.g. after a restart"))
+        .arg(Arg::with_name("abstain")
+            .long("abstain")
+            .help("Don't sign, but still aggregate and relay the signatures of the committee"))
+        .arg(Arg::with_name("observe")
+            .long("observe")
+            .conflicts_with_all(&["block", "secret_key", "abstain"])
+            .help("Observe the aggregation of the committee without being part of it"))
+        .arg(Arg::with_name("relay")
+            .long("relay")
+            .requires("observe")
+            .help("Relay the aggregates while observing"))
+        .arg(Arg::with_name("group_key")
+            .long("group-key")
+            .value_name("HEX")
+            .takes_value(true)
+            .requires("group_threshold")
+            .help("Public key of the group, if the secret key is a share of a threshold key"))
+        .arg(Arg::with_name("group_threshold")
+            .long("group-threshold")
+            .value_name("NUM")
+            .takes_value(true)
+            .requires("group_key")
+            .help("Number of partial signatures needed for a signature of the group"))
         .get_matches();
 
 
-    // parse secret key
-    let sk_raw = hex::decode(matches.value_of("secret_key").expect("No secret key"))?;
-    let key_pair: KeyPair = Deserialize::deserialize_from_vec(&sk_raw)
-        .map_err(|e| IoError::from(e))?;
+    let committee = load_committee(matches.value_of("committee").unwrap())?;
 
-    // create handel config from command line
-    let config = Config {
-        threshold: matches.value_of("threshold").expect("No threshold").parse()?,
-        message_hash: matches.value_of("message").expect("No message").hash::<Blake2bHash>(),
-        node_identity: Arc::new(Identity::new(
-            matches.value_of("id").expect("No ID").parse()?,
-            key_pair.public.clone(),
-            matches.value_of("address").expect("No address").parse()?,
-            1
-        )),
-        disable_shuffling: true,
-        update_count: 1,
-        update_period: Duration::from_millis(100),
-        timeout: Duration::from_millis(500),
-        peer_count: 10,
-        key_pair,
+    let threshold_key = match (matches.value_of("group_key"), matches.value_of("group_threshold")) {
+        (Some(group_key), Some(group_threshold)) => {
+            let group_public_key: PublicKey = Deserialize::deserialize_from_vec(&hex::decode(group_key)?)
+                .map_err(|e| IoError::from(e))?;
+            Some(ThresholdKey { group_public_key, threshold: group_threshold.parse()? })
+        },
+        _ => None,
     };
 
-    // TODO: load identities from file
-    let identity_registry = IdentityRegistry::new();
+    let params = AggregateParams {
+        threshold: matches.value_of("threshold").map(|threshold| threshold.parse()).transpose()?,
+        bind_to: matches.value_of("port")
+            .map(|port| port.parse().map(|port| SocketAddr::new("0.0.0.0".parse().expect("Invalid IP address"), port)))
+            .transpose()?,
+        catch_up: matches.is_present("catch_up"),
+        abstain: matches.is_present("abstain"),
+        relay: matches.is_present("relay"),
+        threshold_key,
+        extra_time: matches.value_of("extra_time").map(|ms| ms.parse().map(Duration::from_millis)).transpose()?,
+        verifier: VerifierBackend::ThreadPool {
+            num_workers: matches.value_of("workers").map(|n| n.parse()).transpose()?,
+        },
+        ..Default::default()
+    };
 
-    // start network layer
-    let mut network = UdpNetwork::new();
-    let bind_to = SocketAddr::new(
-        "0.0.0.0".parse().expect("Invalid IP address"),
-        matches.value_of("port").expect("No port").parse()?,
-    );
+    if matches.is_present("observe") {
+        let message_hash = matches.value_of("message").unwrap().hash::<Blake2bHash>();
 
-    // initialize agent
-    let agent = Arc::new(HandelAgent::new(config, identity_registry, network.sink()));
+        tokio::run(observe(committee, message_hash, params)
+            .map(|signature| info!("Signature: {:#?}", signature))
+            .map_err(|e| error!("Observing failed: {}", e)));
 
-    let main_fut = network
-        .connect(&bind_to, Arc::clone(&agent))
-        .expect("Failed to initialize network")
-        .join(agent.spawn()).map(|_| ());
+        return Ok(());
+    }
 
-    // run everything
-    tokio::run(main_fut);
+    // parse secret key
+    let sk_raw = hex::decode(matches.value_of("secret_key").unwrap())?;
+    let key_pair: KeyPair = Deserialize::deserialize_from_vec(&sk_raw)
+        .map_err(|e| IoError::from(e))?;
+
+    if let Some(block) = matches.value_of("block") {
+        // run prepare and commit phase for the block
+        let header_raw = hex::decode(block)?;
+        let header: MacroHeader = Deserialize::deserialize_from_vec(&header_raw)
+            .map_err(|e| IoError::from(e))?;
+
+        tokio::run(pbft_aggregate(committee, key_pair, header, params)
+            .map(|proof| info!("Proof: {}", hex::encode(proof.serialize_to_vec())))
+            .map_err(|e| error!("pBFT failed: {}", e)));
+    }
+    else {
+        let message_hash = matches.value_of("message").unwrap().hash::<Blake2bHash>();
+
+        tokio::run(aggregate(committee, key_pair, message_hash, params)
+            .map(|signature| info!("Signature: {:#?}", signature))
+            .map_err(|e| error!("Aggregation failed: {}", e)));
+    }
 
     Ok(())
 }
@@ -142,6 +218,42 @@ fn run_testnet() -> Result<(), Error> {
             .value_name("NUM")
             .takes_value(true)
             .default_value("16"))
+        .arg(Arg::with_name("workers")
+            .long("workers")
+            .value_name("NUM")
+            .takes_value(true)
+            .help("Number of threads used for signature verification per node (default: number of CPUs)"))
+        .arg(Arg::with_name("batch_size")
+            .long("batch-size")
+            .value_name("NUM")
+            .takes_value(true)
+            .help("Verify signatures in batches of this size"))
+        .arg(Arg::with_name("dummy_verifier")
+            .long("dummy-verifier")
+            .help("Don't verify signatures"))
+        .arg(Arg::with_name("extra_time")
+            .long("extra-time")
+            .value_name("MS")
+            .takes_value(true)
+            .help("Keep improving the signature for this long after reaching the threshold"))
+        .arg(Arg::with_name("slots")
+            .long("slots")
+            .value_name("NUM")
+            .takes_value(true)
+            .default_value("1")
+            .help("Number of slots of every node"))
+        .arg(Arg::with_name("no_fast_path")
+            .long("no-fast-path")
+            .help("Disable sending complete aggregates immediately"))
+        .arg(Arg::with_name("hierarchical")
+            .long("hierarchical")
+            .value_name("LEVELS")
+            .takes_value(true)
+            .help("Aggregate in sub-committees of this many levels first, and then between their representatives"))
+        .arg(Arg::with_name("compare")
+            .long("compare")
+            .requires("hierarchical")
+            .help("Run flat Handel too and compare it with hierarchical Handel"))
         .get_matches();
 
     let num_nodes = matches.value_of("nodes").unwrap()
@@ -150,16 +262,38 @@ fn run_testnet() -> Result<(), Error> {
     // create testnet
     let mut seed = [0; 32];
     seed.copy_from_slice(b"HandelTestNetSeed_______________");
-    let testnet = TestNet::new(num_nodes, seed);
-
-    let mut nodes = Vec::new();
-    for id in 0..num_nodes {
-        nodes.push(testnet.create_node(id));
+    let mut testnet = TestNet::new(num_nodes, seed);
+    testnet.fast_path = !matches.is_present("no_fast_path");
+    testnet.slots_per_node = matches.value_of("slots").unwrap().parse()?;
+    testnet.extra_time = matches.value_of("extra_time").map(|ms| ms.parse().map(Duration::from_millis)).transpose()?;
+    testnet.verifier = if matches.is_present("dummy_verifier") {
+        VerifierBackend::Dummy
     }
+    else if let Some(batch_size) = matches.value_of("batch_size") {
+        VerifierBackend::Batch {
+            num_workers: matches.value_of("workers").map(|n| n.parse()).transpose()?,
+            batch_size: batch_size.parse()?,
+            batch_delay: Duration::from_millis(10),
+        }
+    }
+    else {
+        VerifierBackend::ThreadPool {
+            num_workers: matches.value_of("workers").map(|n| n.parse()).transpose()?,
+        }
+    };
+
+    testnet.hierarchy = matches.value_of("hierarchical").map(|levels| levels.parse()).transpose()?;
 
     // run everything
-    tokio::run(future::join_all(nodes)
-        .map(|_| ()));
+    let report = testnet.run();
+    info!("Report: {:?}", report);
+
+    if matches.is_present("compare") {
+        testnet.hierarchy = None;
+        let flat = testnet.run();
+        info!("Report (flat): {:?}", flat);
+        info!("Hierarchical vs. flat: latency={}ms/{}ms, sent={}/{}", report.latency_ms, flat.latency_ms, report.sent_count, flat.sent_count);
+    }
 
     Ok(())
 }
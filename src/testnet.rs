@@ -3,22 +3,54 @@ use std::sync::Arc;
 use std::time::{Duration};
 
 use rand_chacha::ChaChaRng;
-use rand::SeedableRng;
+use rand::{SeedableRng, random};
 use futures::{future, Future, IntoFuture};
+use futures::sync::oneshot::Receiver;
+use parking_lot::Mutex;
 use stopwatch::Stopwatch;
 
 use bls::bls12_381::KeyPair;
 use hash::{Hash, Blake2bHash};
 
-use crate::handel::{
-    IdentityRegistry, Identity, Config, UdpNetwork, HandelAgent, AgentProcessor,
+use handel::{
+    IdentityRegistry, Identity, Config, UdpNetwork, HandelAgent, AgentProcessor, VerifierBackend,
+    HandelResult, Hierarchy, TierRouter, Shutdown,
 };
 
 
+/// Measurements of a testnet run
+#[derive(Clone, Debug, Default)]
+pub struct TestNetReport {
+    /// Number of nodes that produced the final signature of the committee
+    pub finished: usize,
+
+    /// Time until the last of these nodes produced it
+    pub latency_ms: i64,
+
+    /// Total number of packets that were sent
+    pub sent_count: usize,
+}
+
+impl TestNetReport {
+    fn finished(&mut self, elapsed_ms: i64) {
+        self.finished += 1;
+        self.latency_ms = self.latency_ms.max(elapsed_ms);
+    }
+}
+
+
 
 pub struct TestNet {
     pub num_nodes: usize,
+    pub verifier: VerifierBackend,
+    pub fast_path: bool,
+    pub extra_time: Option<Duration>,
+    /// Number of slots of every node
+    pub slots_per_node: usize,
+    /// Run hierarchical Handel with sub-committees of this many levels
+    pub hierarchy: Option<usize>,
     key_pairs: Vec<KeyPair>,
+    report: Arc<Mutex<TestNetReport>>,
 }
 
 impl TestNet {
@@ -32,7 +64,13 @@ impl TestNet {
 
         TestNet {
             num_nodes,
+            verifier: VerifierBackend::default(),
+            fast_path: true,
+            extra_time: None,
+            slots_per_node: 1,
+            hierarchy: None,
             key_pairs,
+            report: Arc::new(Mutex::new(TestNetReport::default())),
         }
     }
 
@@ -43,11 +81,12 @@ impl TestNet {
     }
 
     pub fn identity(&self, id: usize) -> Identity {
+        // the node with index `id` owns the slots `id * slots_per_node ..`
         Identity::new(
-            id,
+            id * self.slots_per_node,
             self.key_pair(id).public,
             SocketAddr::new("127.0.0.1".parse().unwrap(), (12000 + id) as u16),
-            1
+            self.slots_per_node
         )
     }
 
@@ -55,28 +94,70 @@ impl TestNet {
         let mut registry = IdentityRegistry::new();
 
         for id in 0..self.num_nodes {
-            registry.insert(Arc::new(self.identity(id)));
+            registry.insert(Arc::new(self.identity(id)))
+                .unwrap_or_else(|e| panic!("Invalid testnet identity: {}", e));
         }
 
         registry
     }
 
     pub fn threshold(&self) -> usize {
-        (2 * self.num_nodes) / 3
+        // all nodes have the same number of slots
+        (2 * self.num_nodes * self.slots_per_node) / 3 + 1
     }
 
     pub fn config(&self, id: usize) -> Config {
         Config {
             threshold: self.threshold(),
             message_hash: b"foobar".hash::<Blake2bHash>(),
+            multi_value: false,
+            session: 0,
             node_identity: Arc::new(self.identity(id)),
+            observer: false,
+            relay: false,
+            threshold_key: None,
+            peers: None,
+            contribution: None,
             disable_shuffling: false,
+            seed: random(),
             update_count: 1,
             update_period: Duration::from_millis(100),
             timeout: Duration::from_millis(500),
             peer_count: 10,
-            key_pair: self.key_pair(id),
+            fast_path: self.fast_path,
+            fast_path_count: 10,
+            catch_up: false,
+            blacklist_threshold: 1,
+            window_initial: 16,
+            window_min: 1,
+            window_max: 128,
+            max_todos: 64,
+            deadline: Some(Duration::from_secs(60)),
+            extra_time: self.extra_time,
+            linger: Some(Duration::from_secs(5)),
+            key_pair: Some(self.key_pair(id)),
+            verifier: self.verifier.clone(),
+            // the testnet is a simulation, so it's fine to use the dummy verifier
+            allow_dummy_verifier: true,
+        }
+    }
+
+    /// Runs all nodes until they stopped
+    pub fn run(&self) -> TestNetReport {
+        *self.report.lock() = TestNetReport::default();
+
+        let mut nodes = Vec::new();
+        for id in 0..self.num_nodes {
+            nodes.push(match self.hierarchy {
+                Some(levels) => self.create_hierarchical_node(id, levels),
+                None => self.create_node(id),
+            });
         }
+
+        tokio::run(future::join_all(nodes)
+            .map(|_| ()));
+
+        self.report.lock().clone()
     }
 
     pub fn create_node(&self, id: usize) -> Box<dyn Future<Item=(), Error=()> + Send>{
@@ -92,35 +173,152 @@ impl TestNet {
 
         // initialize agent
         let agent = Arc::new(HandelAgent::new(self.config(id), self.identity_registry(), network.sink()));
+        let (agent_fut, handle) = agent.spawn();
+        let final_signature = agent.final_signature().unwrap();
+        let maximal = agent.maximal_signature().unwrap();
+        let report = Arc::clone(&self.report);
 
 
         Box::new(future::lazy(move|| {
-            let mut stopwatch = Stopwatch::start_new();
+            let stopwatch = Stopwatch::start_new();
+
+            // the agent lingers after the final signature, so it's timed separately
+            tokio::spawn(log_final_signature(id, final_signature, stopwatch, Arc::clone(&report)));
 
             tokio::spawn(network
-                .connect(&bind_to, Arc::clone(&agent))
+                .connect(&bind_to, Arc::clone(&agent), handle.shutdown_signal())
                 .expect("Failed to initialize network")
-                .join(agent.spawn()).map(|_| ())
+                .join(agent_fut).map(|_| ())
                 .and_then(move |_| {
-                    let agent = Arc::clone(&agent);
-                    agent.final_signature().unwrap()
-                        .map_err(|e| error!("Final signature error: {}", e))
-                        .and_then(move |result| {
-                            stopwatch.stop();
-
-                            match result {
-                                Ok(signature) => {
-                                    info!("[Node {}] Finished with signature: {:#?}", id, signature);
-                                    let stats = stats.read();
-                                    info!("[Node {}] Stats: time={}, signatures={}, sent={}, received={}", id, stopwatch.elapsed_ms(), signature.len(), stats.sent_count, stats.received_count);
-                                },
-                                Err(e) => error!("[Node {}] Finished with error: {:?}", id, e),
-                            }
+                    let stats = stats.read();
+                    info!("[Node {}] Stats: sent={}, received={}", id, stats.sent_count, stats.received_count);
+                    report.lock().sent_count += stats.sent_count;
 
-                            future::ok::<(), ()>(())
+                    maximal
+                        .map_err(|e| error!("Maximal signature error: {}", e))
+                        .map(move |result| {
+                            if let Ok(signature) = result {
+                                info!("[Node {}] Maximal signature: signatures={}, weight={}", id, signature.multisig.len(), signature.weight);
+                            }
                         })
                 })
             ).into_future()
         }))
     }
+
+    /// Creates a node of hierarchical Handel. The node first aggregates the signatures of its
+    /// sub-committee. If it's the representative of the sub-committee, it then contributes the
+    /// aggregate to the second tier, in which all representatives aggregate their contributions.
+    pub fn create_hierarchical_node(&self, id: usize, levels: usize) -> Box<dyn Future<Item=(), Error=()> + Send> {
+        let identity = self.identity(id);
+        let identities = self.identity_registry();
+        let hierarchy = Hierarchy::new(Arc::new(identities.clone()), levels);
+        let config = self.config(id);
+        let is_representative = hierarchy.is_representative(identity.id);
+
+        // both tiers share the network
+        let mut network = UdpNetwork::new();
+        let stats = Arc::clone(&network.statistics);
+        let sink = network.sink();
+        let bind_to = SocketAddr::new(
+            "0.0.0.0".parse().expect("Invalid IP address"),
+            identity.address.port()
+        );
+
+        // initialize agent of the first tier
+        let (first_config, sub_committee) = hierarchy.first_tier(&config);
+        let first = Arc::new(HandelAgent::new(first_config, sub_committee, network.sink()));
+        let (first_fut, _) = first.spawn();
+        let first_signature = first.maximal_signature().unwrap();
+        let second_session = if is_representative { hierarchy.second_tier_session(&config) } else { None };
+        let router = Arc::new(TierRouter::new(Arc::clone(&first), second_session));
+
+        // the network runs until the agents of both tiers stopped
+        let shutdown = Shutdown::new();
+        let report = Arc::clone(&self.report);
+
+
+        Box::new(future::lazy(move || {
+            let stopwatch = Stopwatch::start_new();
+
+            let network_fut = network
+                .connect(&bind_to, Arc::clone(&router), shutdown.signal())
+                .expect("Failed to initialize network");
+            {
+                let report = Arc::clone(&report);
+                tokio::spawn(network_fut.map(move |_| {
+                    let stats = stats.read();
+                    info!("[Node {}] Stats: sent={}, received={}", id, stats.sent_count, stats.received_count);
+                    report.lock().sent_count += stats.sent_count;
+                }));
+            }
+
+            let second_fut = first_signature
+                .map_err(|e| error!("First tier signature error: {}", e))
+                .and_then(move |result| -> Box<dyn Future<Item=(), Error=()> + Send> {
+                    // with an error, we still contribute the best aggregate we have
+                    let multisig = match result {
+                        Ok(signature) => {
+                            info!("[Node {}] Finished first tier: signatures={}, weight={}", id, signature.multisig.len(), signature.weight);
+                            Some(signature.multisig)
+                        },
+                        Err(e) => {
+                            error!("[Node {}] First tier finished with error: {}", id, e);
+                            e.partial_result().best
+                        },
+                    };
+
+                    match multisig {
+                        Some(multisig) if is_representative => {
+                            let second_config = match hierarchy.second_tier(&config, multisig) {
+                                Some(second_config) => second_config,
+                                None => {
+                                    error!("[Node {}] Session of the second tier overflows", id);
+                                    return Box::new(future::ok(()));
+                                },
+                            };
+                            let second = Arc::new(HandelAgent::new(second_config, identities, sink));
+                            let (second_fut, _) = second.spawn();
+                            let final_signature = second.final_signature().unwrap();
+                            router.set_second(second);
+
+                            tokio::spawn(log_final_signature(id, final_signature, stopwatch, report));
+                            second_fut
+                        },
+                        _ => Box::new(future::ok(())),
+                    }
+                });
+
+            tokio::spawn(first_fut.join(second_fut)
+                .then(move |_| {
+                    shutdown.trigger();
+                    Ok::<(), ()>(())
+                }))
+                .into_future()
+        }))
+    }
+}
+
+
+/// Logs the final signature of a node and records when it was produced
+fn log_final_signature(id: usize, final_signature: Receiver<HandelResult>, mut stopwatch: Stopwatch, report: Arc<Mutex<TestNetReport>>) -> impl Future<Item=(), Error=()> {
+    final_signature
+        .map_err(|e| error!("Final signature error: {}", e))
+        .map(move |result| {
+            stopwatch.stop();
+
+            match result {
+                Ok(signature) => {
+                    info!("[Node {}] Finished with signature: {:#?}", id, signature.multisig);
+                    info!("[Node {}] Stats: time={}, signatures={}, weight={}", id, stopwatch.elapsed_ms(), signature.multisig.len(), signature.weight);
+                    report.lock().finished(stopwatch.elapsed_ms());
+                },
+                Err(e) => {
+                    error!("[Node {}] Finished with error: {}", id, e);
+                    for level in e.partial_result().levels.iter() {
+                        info!("[Node {}] Level {}: weight={}/{}, complete={}", id, level.level, level.weight, level.total_weight, level.complete);
+                    }
+                },
+            }
+        })
 }